@@ -0,0 +1,744 @@
+use super::crypto::{self, Encryption, Keys};
+use super::error::Error;
+use super::manifest::{self, Manifest};
+use super::util::{self, BackupOptions, Failures, Symlinks};
+use age::stream::StreamWriter;
+use bzip2::read::BzDecoder;
+use bzip2::write::BzEncoder;
+use chrono::Utc;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use rayon::prelude::*;
+use serde::Deserialize;
+use std::ffi::OsStr;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Cursor, Read, Seek, SeekFrom, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+use tar::{Archive, Builder, EntryType, Header};
+use xz2::read::XzDecoder;
+use xz2::stream::MtStreamBuilder;
+use xz2::write::XzEncoder;
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+// What a backup is written as
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    Dir,
+    Tar,
+    Zip,
+}
+
+impl FromStr for Format {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Format, String> {
+        match s {
+            "dir" => Ok(Format::Dir),
+            "tar" => Ok(Format::Tar),
+            "zip" => Ok(Format::Zip),
+            _ => Err(format!(
+                "Unknown format {}, expected one of dir, tar or zip",
+                s
+            )),
+        }
+    }
+}
+
+// Compression applied on top of a tar archive
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    Gzip,
+    Bzip2,
+    Xz,
+    Zstd,
+    None,
+}
+
+impl Compression {
+    pub fn extension(self) -> &'static str {
+        match self {
+            Compression::Gzip => ".tar.gz",
+            Compression::Bzip2 => ".tar.bz2",
+            Compression::Xz => ".tar.xz",
+            Compression::Zstd => ".tar.zst",
+            Compression::None => ".tar",
+        }
+    }
+    // Check a requested level against the codec, falling back to its default
+    fn level(self, level: Option<u32>) -> Result<u32, Error> {
+        let (min, max, default) = self.levels();
+        let level = level.unwrap_or(default);
+        if self != Compression::None && (level < min || level > max) {
+            return Err(Error::InvalidInput(format!(
+                "Compression level {} is not supported by {}, expected {} to {}",
+                level, self, min, max
+            )));
+        }
+        Ok(level)
+    }
+    // Levels accepted by the codec and the one used when none is given
+    fn levels(self) -> (u32, u32, u32) {
+        match self {
+            Compression::Gzip => (0, 9, 6),
+            Compression::Bzip2 => (1, 9, 9),
+            Compression::Xz => (0, 9, 6),
+            Compression::Zstd => (1, 22, 3),
+            Compression::None => (0, 0, 0),
+        }
+    }
+    // Work out the codec of an archive from its first bytes
+    fn detect(magic: &[u8]) -> Compression {
+        if magic.starts_with(&[0x1F, 0x8B]) {
+            Compression::Gzip
+        } else if magic.starts_with(b"BZh") {
+            Compression::Bzip2
+        } else if magic.starts_with(&[0xFD, b'7', b'z', b'X', b'Z', 0x00]) {
+            Compression::Xz
+        } else if magic.starts_with(&[0x28, 0xB5, 0x2F, 0xFD]) {
+            Compression::Zstd
+        } else {
+            Compression::None
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Compression, String> {
+        match s {
+            "gzip" | "gz" => Ok(Compression::Gzip),
+            "bzip2" | "bz2" => Ok(Compression::Bzip2),
+            "xz" => Ok(Compression::Xz),
+            "zstd" | "zst" => Ok(Compression::Zstd),
+            "none" => Ok(Compression::None),
+            _ => Err(format!(
+                "Unknown compression {}, expected one of gzip, bzip2, xz, zstd or none",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Compression::Gzip => "gzip",
+            Compression::Bzip2 => "bzip2",
+            Compression::Xz => "xz",
+            Compression::Zstd => "zstd",
+            Compression::None => "none",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+// Output stream of an archive, wrapping the file in the chosen encoder
+enum Encoder<W: Write> {
+    Gzip(GzEncoder<W>),
+    Bzip2(BzEncoder<W>),
+    Xz(XzEncoder<W>),
+    Zstd(zstd::Encoder<'static, W>),
+    None(W),
+}
+
+impl<W: Write> Encoder<W> {
+    // xz and zstd compress on `threads` threads, their output doesn't depend
+    // on how many
+    fn new(
+        output: W,
+        compression: Compression,
+        level: u32,
+        threads: usize,
+    ) -> io::Result<Encoder<W>> {
+        Ok(match compression {
+            Compression::Gzip => {
+                Encoder::Gzip(GzEncoder::new(output, flate2::Compression::new(level)))
+            }
+            Compression::Bzip2 => {
+                Encoder::Bzip2(BzEncoder::new(output, bzip2::Compression::new(level)))
+            }
+            Compression::Xz if threads > 1 => {
+                let stream = MtStreamBuilder::new()
+                    .threads(threads as u32)
+                    .preset(level)
+                    .encoder()?;
+                Encoder::Xz(XzEncoder::new_stream(output, stream))
+            }
+            Compression::Xz => Encoder::Xz(XzEncoder::new(output, level)),
+            Compression::Zstd => {
+                let mut encoder = zstd::Encoder::new(output, level as i32)?;
+                if threads > 1 {
+                    encoder.multithread(threads as u32)?;
+                }
+                Encoder::Zstd(encoder)
+            }
+            Compression::None => Encoder::None(output),
+        })
+    }
+    fn finish(self) -> io::Result<W> {
+        match self {
+            Encoder::Gzip(e) => e.finish(),
+            Encoder::Bzip2(e) => e.finish(),
+            Encoder::Xz(e) => e.finish(),
+            Encoder::Zstd(e) => e.finish(),
+            Encoder::None(w) => Ok(w),
+        }
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Encoder::Gzip(e) => e.write(buf),
+            Encoder::Bzip2(e) => e.write(buf),
+            Encoder::Xz(e) => e.write(buf),
+            Encoder::Zstd(e) => e.write(buf),
+            Encoder::None(w) => w.write(buf),
+        }
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Encoder::Gzip(e) => e.flush(),
+            Encoder::Bzip2(e) => e.flush(),
+            Encoder::Xz(e) => e.flush(),
+            Encoder::Zstd(e) => e.flush(),
+            Encoder::None(w) => w.flush(),
+        }
+    }
+}
+
+// File an archive is written to, through age when it is encrypted
+enum Output {
+    Plain(File),
+    Encrypted(StreamWriter<File>),
+}
+
+impl Output {
+    fn create(path: &Path, encryption: Option<&Encryption>) -> Result<Output, Error> {
+        let file = File::create(path).map_err(Error::with_path(path))?;
+        Ok(match encryption {
+            Some(encryption) => Output::Encrypted(encryption.wrap(file)?),
+            None => Output::Plain(file),
+        })
+    }
+    // Write the last encrypted chunk, the archive is truncated without it
+    fn finish(self) -> io::Result<()> {
+        match self {
+            Output::Plain(_) => Ok(()),
+            Output::Encrypted(w) => w.finish().map(|_| ()),
+        }
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Output::Plain(f) => f.write(buf),
+            Output::Encrypted(w) => w.write(buf),
+        }
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Output::Plain(f) => f.flush(),
+            Output::Encrypted(w) => w.flush(),
+        }
+    }
+}
+
+// Name of the archive written for the backup directory `out`
+pub(crate) fn archive_path(out: &Path, compression: Compression, encrypted: bool) -> PathBuf {
+    let mut name = out.as_os_str().to_os_string();
+    name.push(compression.extension());
+    if encrypted {
+        return crypto::encrypted_path(PathBuf::from(name));
+    }
+    PathBuf::from(name)
+}
+
+// Write every path straight into a tarball next to where `out` would be.
+// Entries live under a single directory named after `out`
+pub(crate) fn write_tar(
+    paths: &[PathBuf],
+    manifest: &Manifest,
+    print: &util::VerbosePrint,
+    out: &Path,
+    options: &BackupOptions,
+    failures: &mut Failures,
+) -> Result<(), Error> {
+    let root = out
+        .file_name()
+        .unwrap_or_else(|| OsStr::new("confbk_backup"));
+    let compression = options.compression;
+    let level = compression.level(options.level)?;
+    let archive = archive_path(out, compression, options.encrypt.is_some());
+    print.debug(&format!(
+        "Writing {} archive \"{}\"",
+        compression,
+        archive.display()
+    ));
+    let output = Output::create(&archive, options.encrypt.as_ref())?;
+    let encoder = Encoder::new(output, compression, level, rayon::current_num_threads())?;
+    let mut builder = Builder::new(encoder);
+    builder.follow_symlinks(manifest.symlinks == Symlinks::Follow);
+    builder.sparse(true);
+    let json = manifest.to_json()?;
+    let mut header = Header::new_gnu();
+    header.set_size(json.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(Utc::now().timestamp().max(0) as u64);
+    builder.append_data(
+        &mut header,
+        Path::new(root).join(manifest::MANIFEST_NAME),
+        &json[..],
+    )?;
+    let hardlinks = manifest.hardlinks();
+    for file in paths {
+        let name = Path::new(root).join(util::backup_path(file));
+        if let Some(first) = hardlinks.get(file.as_path()) {
+            print.debug(&format!(
+                "Adding \"{}\" as a link to \"{}\"",
+                name.display(),
+                first.display()
+            ));
+            let metadata = fs::metadata(file).map_err(Error::with_path(file))?;
+            let mut header = Header::new_gnu();
+            header.set_metadata(&metadata);
+            header.set_entry_type(EntryType::Link);
+            header.set_size(0);
+            builder
+                .append_link(&mut header, &name, Path::new(root).join(first))
+                .map_err(Error::with_path(file))?;
+            continue;
+        }
+        print.debug(&format!(
+            "Adding file \"{}\" as \"{}\"",
+            file.display(),
+            name.display()
+        ));
+        if let Some(content) = manifest.redacted.get(file) {
+            let metadata = fs::metadata(file).map_err(Error::with_path(file))?;
+            let mut header = Header::new_gnu();
+            header.set_metadata(&metadata);
+            header.set_size(content.len() as u64);
+            builder
+                .append_data(&mut header, &name, &content[..])
+                .map_err(Error::with_path(file))?;
+            continue;
+        }
+        let result = if file.is_dir() && !util::is_symlink(file) {
+            builder.append_dir_all(&name, file)
+        } else {
+            builder.append_path_with_name(file, &name)
+        };
+        if let Err(e) = result {
+            failures.add(file, Error::with_path(file)(e))?;
+        }
+    }
+    builder.into_inner()?.finish()?.finish()?;
+    Ok(())
+}
+
+// Name of the zip written for the backup directory `out`
+pub(crate) fn zip_path(out: &Path, encrypted: bool) -> PathBuf {
+    let mut name = out.as_os_str().to_os_string();
+    name.push(".zip");
+    if encrypted {
+        return crypto::encrypted_path(PathBuf::from(name));
+    }
+    PathBuf::from(name)
+}
+
+// Write every path into a deflated zip, laid out like a backup directory.
+// Unix permissions are kept in each entry's attributes. Zips are written
+// with seeks, so an encrypted one is put together in memory first
+pub(crate) fn write_zip(
+    paths: &[PathBuf],
+    manifest: &Manifest,
+    print: &util::VerbosePrint,
+    out: &Path,
+    options: &BackupOptions,
+    failures: &mut Failures,
+) -> Result<(), Error> {
+    let root = out
+        .file_name()
+        .unwrap_or_else(|| OsStr::new("confbk_backup"));
+    let level = Compression::Gzip.level(options.level)?;
+    let archive = zip_path(out, options.encrypt.is_some());
+    print.debug(&format!("Writing zip archive \"{}\"", archive.display()));
+    match &options.encrypt {
+        Some(encryption) => {
+            let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+            add_files(&mut zip, paths, manifest, print, root, level, failures)?;
+            let bytes = zip.finish()?.into_inner();
+            let mut output = Output::create(&archive, Some(encryption))?;
+            output.write_all(&bytes)?;
+            output.finish()?;
+        }
+        None => {
+            let file = File::create(&archive).map_err(Error::with_path(&archive))?;
+            let mut zip = ZipWriter::new(file);
+            add_files(&mut zip, paths, manifest, print, root, level, failures)?;
+            zip.finish()?;
+        }
+    }
+    Ok(())
+}
+
+// Add the manifest and every path to a zip, under the directory `root`
+fn add_files<W: Write + Seek>(
+    zip: &mut ZipWriter<W>,
+    paths: &[PathBuf],
+    manifest: &Manifest,
+    print: &util::VerbosePrint,
+    root: &OsStr,
+    level: u32,
+    failures: &mut Failures,
+) -> Result<(), Error> {
+    let options = SimpleFileOptions::default()
+        .compression_method(CompressionMethod::Deflated)
+        .compression_level(Some(i64::from(level)))
+        .unix_permissions(0o644);
+    zip.start_file(
+        zip_name(&Path::new(root).join(manifest::MANIFEST_NAME))?,
+        options,
+    )?;
+    zip.write_all(&manifest.to_json()?)?;
+    // zips have no hard links, restore links them to the file they share
+    let hardlinks = manifest.hardlinks();
+    let mut files = Vec::new();
+    for path in paths {
+        let expanded = if path.is_dir() && !util::is_symlink(path) {
+            util::dir_files(path, false, manifest.symlinks, false)?
+        } else {
+            vec![path.to_path_buf()]
+        };
+        for file in expanded {
+            if let Some(first) = hardlinks.get(file.as_path()) {
+                print.debug(&format!(
+                    "Leaving out \"{}\", it is stored as \"{}\"",
+                    file.display(),
+                    first.display()
+                ));
+                continue;
+            }
+            let name = Path::new(root).join(util::backup_path(&file));
+            files.push((file, name));
+        }
+    }
+    // a batch of files at a time is deflated on the worker pool, each into a
+    // zip of its own that is copied over in order
+    for batch in files.chunks(2 * rayon::current_num_threads()) {
+        let deflated: Vec<Result<Deflated, Error>> = batch
+            .par_iter()
+            .map(|(file, name)| {
+                let content = manifest.redacted.get(file).map(Vec::as_slice);
+                deflate(file, name, content, level, manifest.symlinks)
+            })
+            .collect();
+        for ((file, name), deflated) in batch.iter().zip(deflated) {
+            print.debug(&format!(
+                "Adding file \"{}\" as \"{}\"",
+                file.display(),
+                name.display()
+            ));
+            match deflated {
+                Ok(Deflated::Link(target)) => zip.add_symlink(zip_name(name)?, target, options)?,
+                Ok(Deflated::Small(bytes)) => {
+                    zip.merge_archive(ZipArchive::new(Cursor::new(bytes))?)?
+                }
+                Ok(Deflated::Large(mut reader, metadata)) => {
+                    zip.start_file(zip_name(name)?, file_options(level, &metadata))?;
+                    io::copy(&mut reader, zip).map_err(Error::with_path(file))?;
+                }
+                Err(e) => failures.add(file, e)?,
+            }
+        }
+    }
+    Ok(())
+}
+
+// What a file turns into in a zip, prepared on the worker pool
+enum Deflated {
+    /// Target of a preserved link
+    Link(String),
+    /// A zip holding only the deflated file
+    Small(Vec<u8>),
+    /// A file too large to hold in memory, deflated straight into the zip
+    Large(File, fs::Metadata),
+}
+
+// Files up to this size are deflated in memory on the worker pool
+const IN_MEMORY: u64 = 16 * 1024 * 1024;
+
+// Prepare a file to be added to a zip as `name`, or its redacted `content`
+// when it has some. Unreadable files fail here, before they leave an entry behind
+fn deflate(
+    file: &Path,
+    name: &Path,
+    content: Option<&[u8]>,
+    level: u32,
+    symlinks: Symlinks,
+) -> Result<Deflated, Error> {
+    if symlinks != Symlinks::Follow && util::is_symlink(file) {
+        let target = fs::read_link(file).map_err(Error::with_path(file))?;
+        return Ok(Deflated::Link(zip_name(&target)?));
+    }
+    let mut reader = File::open(file).map_err(Error::with_path(file))?;
+    let metadata = reader.metadata().map_err(Error::with_path(file))?;
+    if metadata.len() > IN_MEMORY && content.is_none() {
+        return Ok(Deflated::Large(reader, metadata));
+    }
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    zip.start_file(zip_name(name)?, file_options(level, &metadata))?;
+    match content {
+        Some(content) => zip.write_all(content)?,
+        None => {
+            io::copy(&mut reader, &mut zip).map_err(Error::with_path(file))?;
+        }
+    }
+    Ok(Deflated::Small(zip.finish()?.into_inner()))
+}
+
+// Deflate at `level`, keeping the file's Unix permissions
+fn file_options(level: u32, metadata: &fs::Metadata) -> SimpleFileOptions {
+    SimpleFileOptions::default()
+        .compression_method(CompressionMethod::Deflated)
+        .compression_level(Some(i64::from(level)))
+        .unix_permissions(metadata.permissions().mode())
+        .large_file(metadata.len() >= u64::from(u32::MAX))
+}
+
+// Zip entry names have to be UTF-8
+fn zip_name(name: &Path) -> Result<String, Error> {
+    match name.to_str() {
+        Some(name) => Ok(name.to_string()),
+        None => Err(Error::Archive(format!(
+            "Zip entry {} is not valid UTF-8",
+            name.display()
+        ))),
+    }
+}
+
+// Whether a file is named like an archive written by write_tar or write_zip
+pub(crate) fn is_archive_name(path: &Path) -> bool {
+    let name = path.to_string_lossy();
+    let name = name.trim_end_matches(crypto::EXTENSION);
+    name.ends_with(".zip")
+        || [
+            Compression::Gzip,
+            Compression::Bzip2,
+            Compression::Xz,
+            Compression::Zstd,
+            Compression::None,
+        ]
+        .iter()
+        .any(|c| name.ends_with(c.extension()))
+}
+
+const ZIP_MAGIC: &[u8] = b"PK\x03\x04";
+
+// Whether an archive is a zip rather than a tarball
+fn is_zip(archive: &Path) -> Result<bool, Error> {
+    let mut magic = [0; 4];
+    let read = File::open(archive)
+        .map_err(Error::with_path(archive))?
+        .read(&mut magic)?;
+    Ok(magic[..read] == *ZIP_MAGIC)
+}
+
+// A backup archive opened for reading
+pub(crate) enum Opened {
+    Tar(Archive<Box<dyn Read>>),
+    Zip(ZipArchive<ZipSource>),
+}
+
+// Where a zip is read from, an encrypted one is decrypted into memory since
+// zips are read with seeks
+pub(crate) enum ZipSource {
+    File(File),
+    Memory(Cursor<Vec<u8>>),
+}
+
+impl Read for ZipSource {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            ZipSource::File(f) => f.read(buf),
+            ZipSource::Memory(c) => c.read(buf),
+        }
+    }
+}
+
+impl Seek for ZipSource {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match self {
+            ZipSource::File(f) => f.seek(pos),
+            ZipSource::Memory(c) => c.seek(pos),
+        }
+    }
+}
+
+// Open a tarball or zip produced by write_tar or write_zip, decrypting it with
+// `keys` when it is encrypted, whatever compression it uses
+pub(crate) fn open(backup: &Path, keys: &Keys) -> Result<Opened, Error> {
+    if !crypto::is_encrypted(backup)? && is_zip(backup)? {
+        let file = File::open(backup).map_err(Error::with_path(backup))?;
+        return Ok(Opened::Zip(ZipArchive::new(ZipSource::File(file))?));
+    }
+    let mut reader = BufReader::new(keys.open(backup)?);
+    let magic = reader.fill_buf()?;
+    let compression = Compression::detect(magic);
+    if magic.starts_with(ZIP_MAGIC) {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+        let zip = ZipArchive::new(ZipSource::Memory(Cursor::new(bytes)))?;
+        return Ok(Opened::Zip(zip));
+    }
+    let reader: Box<dyn Read> = match compression {
+        Compression::Gzip => Box::new(GzDecoder::new(reader)),
+        Compression::Bzip2 => Box::new(BzDecoder::new(reader)),
+        Compression::Xz => Box::new(XzDecoder::new(reader)),
+        Compression::Zstd => Box::new(zstd::Decoder::with_buffer(reader)?),
+        Compression::None => Box::new(reader),
+    };
+    Ok(Opened::Tar(Archive::new(reader)))
+}
+
+// Location of an archive entry inside the backup, without the top level directory
+pub(crate) fn stored_path(entry: &Path) -> Result<PathBuf, Error> {
+    let mut components = entry.components();
+    components.next();
+    let stored: PathBuf = components.as_path().to_path_buf();
+    let valid = stored
+        .components()
+        .all(|c| matches!(c, Component::Normal(_) | Component::CurDir));
+    if valid {
+        Ok(stored)
+    } else {
+        Err(Error::Archive(format!(
+            "Archive entry {} is outside of the backup",
+            entry.display()
+        )))
+    }
+}
+
+// Unpack a single archive entry to `dest`, creating parent directories
+pub(crate) fn unpack<R: io::Read>(entry: &mut tar::Entry<R>, dest: &Path) -> Result<(), Error> {
+    if let Some(parent) = dest.parent() {
+        if !parent.as_os_str().is_empty() {
+            fs::create_dir_all(parent).map_err(Error::with_path(parent))?;
+        }
+    }
+    entry.unpack(dest).map_err(Error::with_path(dest))?;
+    Ok(())
+}
+
+// Call `f` with the location and contents of every file stored in a backup
+// directory or archive, the manifest included. Links are read as the path
+// they point to
+pub(crate) fn walk<F>(backup: &Path, keys: &Keys, mut f: F) -> Result<(), Error>
+where
+    F: FnMut(&Path, &mut dyn Read) -> Result<(), Error>,
+{
+    if backup.is_dir() {
+        for file in util::dir_files(backup, false, Symlinks::Preserve, false)? {
+            let stored = file.strip_prefix(backup).unwrap_or(&file);
+            if util::is_symlink(&file) {
+                let target = fs::read_link(&file).map_err(Error::with_path(&file))?;
+                f(stored, &mut target.as_os_str().as_bytes())?;
+                continue;
+            }
+            f(
+                stored,
+                &mut File::open(&file).map_err(Error::with_path(&file))?,
+            )?;
+        }
+        return Ok(());
+    }
+    match open(backup, keys)? {
+        Opened::Zip(mut zip) => {
+            for index in 0..zip.len() {
+                let mut entry = zip.by_index(index)?;
+                if entry.is_dir() {
+                    continue;
+                }
+                let name = zip_entry_path(&entry)?;
+                f(&stored_path(&name)?, &mut entry)?;
+            }
+        }
+        Opened::Tar(mut archive) => {
+            for entry in archive.entries()? {
+                let mut entry = entry?;
+                // hard links have no contents of their own
+                if entry.header().entry_type().is_dir()
+                    || entry.header().entry_type().is_hard_link()
+                {
+                    continue;
+                }
+                let stored = stored_path(&entry.path()?)?;
+                if entry.header().entry_type().is_symlink() {
+                    let target = entry.link_name_bytes().unwrap_or_default().into_owned();
+                    f(&stored, &mut &target[..])?;
+                    continue;
+                }
+                f(&stored, &mut entry)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// Path of a zip entry, refusing names that would escape the backup
+pub(crate) fn zip_entry_path<R: Read>(entry: &zip::read::ZipFile<R>) -> Result<PathBuf, Error> {
+    entry.enclosed_name().ok_or_else(|| {
+        Error::Archive(format!(
+            "Archive entry {} is outside of the backup",
+            entry.name()
+        ))
+    })
+}
+
+// Manifest of a backup directory or archive, if it has one. It is written
+// first, so archives are only read up to it
+pub(crate) fn read_manifest(backup: &Path, keys: &Keys) -> Result<Option<Manifest>, Error> {
+    if backup.is_dir() {
+        let path = backup.join(manifest::MANIFEST_NAME);
+        if !path.is_file() {
+            return Ok(None);
+        }
+        let file = File::open(&path).map_err(Error::with_path(&path))?;
+        return Ok(Some(Manifest::from_json(file)?));
+    }
+    let mut archive = match open(backup, keys)? {
+        Opened::Zip(mut zip) => {
+            for index in 0..zip.len() {
+                let entry = zip.by_index(index)?;
+                if !entry.is_dir()
+                    && stored_path(&zip_entry_path(&entry)?)? == Path::new(manifest::MANIFEST_NAME)
+                {
+                    return Ok(Some(Manifest::from_json(entry)?));
+                }
+            }
+            return Ok(None);
+        }
+        Opened::Tar(archive) => archive,
+    };
+    // the manifest is written first, a tarball that starts with anything else
+    // isn't a backup and isn't read any further
+    for entry in archive.entries()? {
+        let entry = entry?;
+        if entry.header().entry_type().is_dir() {
+            continue;
+        }
+        if stored_path(&entry.path()?)? == Path::new(manifest::MANIFEST_NAME) {
+            return Ok(Some(Manifest::from_json(entry)?));
+        }
+        break;
+    }
+    Ok(None)
+}
@@ -1,20 +1,27 @@
-use super::util::{self, FatalError};
-use fs_extra::dir;
-use std::ffi::OsString;
-use std::fs::File;
-use std::io::{self, BufRead, BufReader};
+use confbk::archive::{Compression, Format};
+use confbk::config::{self, Config};
+use confbk::manifest::HashAlgorithm;
+use confbk::util::{OnExisting, Symlinks};
+use confbk::{
+    expand_path, Encryption, Error, Keys, PathOptions, Scanner, SecretPolicy, SigningKey,
+    VerifyingKey,
+};
 use std::path::PathBuf;
 use structopt::StructOpt;
 
 #[derive(Debug, StructOpt)]
-#[structopt(name = "confbk", about = "Easily backup important files")]
+#[structopt(
+    name = "confbk",
+    about = "Easily backup important files",
+    raw(setting = "structopt::clap::AppSettings::SubcommandsNegateReqs")
+)]
 pub struct Opt {
     #[structopt(short, long, parse(from_os_str))]
-    /// Directory to put the configs in
+    /// Name of the backup, {date}, {time}, {hostname}, {profile} and strftime fields are filled in [default: confbk-{date}]
     out: Option<PathBuf>,
 
-    #[structopt(short, long)]
-    /// List files that would be backed up
+    #[structopt(short, long, raw(alias = r#""dry-run""#))]
+    /// List files that would be backed up, restored or pruned
     dry_run: bool,
 
     #[structopt(short, long, conflicts_with = "verbose")]
@@ -25,106 +32,320 @@ pub struct Opt {
     /// Display more verbose output
     verbose: bool,
 
-    #[structopt(short, long, parse(from_os_str), required_unless = "list")]
+    #[structopt(
+        short,
+        long,
+        parse(from_os_str),
+        raw(required_unless_one = r#"&["list", "profile"]"#)
+    )]
     /// A file that contains filenames of configs (new line delimited)
     file: Option<PathBuf>,
 
-    #[structopt(short, long, parse(from_os_str), required_unless = "file")]
-    /// A list of config files to be backed up
+    #[structopt(
+        short,
+        long,
+        parse(from_os_str),
+        raw(required_unless_one = r#"&["file", "profile"]"#)
+    )]
+    /// A list of config files or globs to be backed up
     list: Vec<PathBuf>,
 
     #[structopt(short, long)]
     /// Compress config dir into a .tar.xz file
     tar: bool,
 
+    #[structopt(short, long)]
+    /// Compress config dir into a tarball using gzip, bzip2, xz, zstd or none
+    compression: Option<Compression>,
+
+    #[structopt(long)]
+    /// Compression level passed to the chosen codec
+    level: Option<u32>,
+
+    #[structopt(long, conflicts_with = "tar")]
+    /// Write the backup as a plain directory, a tarball or a zip (dir, tar or zip)
+    format: Option<Format>,
+
+    #[structopt(long)]
+    /// Hash recorded in the manifest for every file (sha256 or blake3)
+    hash: Option<HashAlgorithm>,
+
+    #[structopt(long, parse(from_os_str))]
+    /// Only store files that changed since this earlier backup, recording deletions
+    incremental: Option<PathBuf>,
+
+    #[structopt(
+        long,
+        parse(from_os_str),
+        raw(conflicts_with_all = r#"&["tar", "compression", "level", "format", "incremental"]"#)
+    )]
+    /// Store the backup as a deduplicated snapshot in this repository
+    repo: Option<PathBuf>,
+
+    #[structopt(long = "on-existing")]
+    /// What to do when the backup already exists (fail, overwrite, merge, rename or update)
+    on_existing: Option<OnExisting>,
+
+    #[structopt(long)]
+    /// Keep symbolic links as links, back up what they point to, or leave them out (preserve, follow or skip)
+    symlinks: Option<Symlinks>,
+
+    #[structopt(long = "keep-going")]
+    /// Back up every file that can be read and list the ones that could not at the end
+    keep_going: bool,
+
+    #[structopt(short, long)]
+    /// Copy, hash and compress this many files at once [default: 1]
+    jobs: Option<usize>,
+
+    #[structopt(long)]
+    /// Encrypt to an age public key, every key in a file, or with $CONFBK_PASSPHRASE when given passphrase
+    encrypt: Option<String>,
+
+    #[structopt(long, parse(from_os_str), raw(number_of_values = "1"))]
+    /// age identity file to decrypt backups with, $CONFBK_PASSPHRASE is tried as well
+    identity: Vec<PathBuf>,
+
+    #[structopt(long, parse(from_os_str))]
+    /// minisign secret key to write a detached .minisig signature of the backup with
+    sign: Option<PathBuf>,
+
+    #[structopt(long)]
+    /// Scan for secrets first and warn, skip the files or redact them [possible values: warn, skip, redact]
+    secrets: Option<SecretPolicy>,
+
+    #[structopt(long = "secret-pattern", raw(number_of_values = "1"))]
+    /// Also treat matches of this regex as secrets, scanning with warn unless --secrets is given
+    secret_pattern: Vec<String>,
+
     #[structopt(short, long, parse(from_os_str))]
-    /// Exclude files or directories in the backup
+    /// Exclude files or directories matching a glob, a leading ! keeps matches instead
     exclude: Vec<PathBuf>,
+
+    #[structopt(long = "no-ignore")]
+    /// Back up files in directories even if .gitignore, .ignore or .confbkignore skip them
+    no_ignore: bool,
+
+    #[structopt(long, parse(from_os_str))]
+    /// Config file to read profiles from [default: ~/.config/confbk/config.toml]
+    config: Option<PathBuf>,
+
+    #[structopt(short, long)]
+    /// Profile from the config file to take settings from
+    profile: Option<String>,
+
+    #[structopt(subcommand)]
+    cmd: Option<Command>,
+}
+
+#[derive(Debug, StructOpt)]
+pub enum Command {
+    #[structopt(name = "restore")]
+    /// Restore a backup to the location it was taken from
+    Restore {
+        #[structopt(parse(from_os_str))]
+        /// Backup directory or archive to restore, or a snapshot id with --repo
+        backup: PathBuf,
+        #[structopt(long = "no-owner")]
+        /// Keep restored files owned by the current user instead of their original owner
+        no_owner: bool,
+        #[structopt(long)]
+        /// Refuse the backup unless it is signed with this minisign public key (a file or the key itself)
+        signature: Option<String>,
+    },
+    #[structopt(name = "verify")]
+    /// Check a backup against the hashes recorded in its manifest
+    Verify {
+        #[structopt(parse(from_os_str))]
+        /// Backup directory or archive to verify, or a snapshot id with --repo
+        backup: PathBuf,
+        #[structopt(long)]
+        /// Check the backup is signed with this minisign public key (a file or the key itself)
+        signature: Option<String>,
+    },
+    #[structopt(name = "list")]
+    /// List backups with their date, file count and size
+    List {
+        #[structopt(long, parse(from_os_str), default_value = ".")]
+        /// Directory holding the backups, unless --repo is given
+        dir: PathBuf,
+    },
+    #[structopt(name = "show")]
+    /// Print where a backup was taken and every file in it
+    Show {
+        /// Name of a backup in --dir, or a snapshot id with --repo
+        id: String,
+        #[structopt(long, parse(from_os_str), default_value = ".")]
+        /// Directory holding the backups, unless --repo is given
+        dir: PathBuf,
+    },
+    #[structopt(name = "prune")]
+    /// Delete old backups, keeping those picked by the --keep options
+    Prune {
+        #[structopt(long = "keep-last")]
+        /// Keep this many of the newest backups
+        keep_last: Option<usize>,
+        #[structopt(long = "keep-daily")]
+        /// Keep the newest backup of this many days
+        keep_daily: Option<usize>,
+        #[structopt(long = "keep-weekly")]
+        /// Keep the newest backup of this many weeks
+        keep_weekly: Option<usize>,
+        #[structopt(long = "keep-monthly")]
+        /// Keep the newest backup of this many months
+        keep_monthly: Option<usize>,
+        #[structopt(long, parse(from_os_str), default_value = ".")]
+        /// Directory holding the backups, unless --repo is given
+        dir: PathBuf,
+    },
+}
+
+// Public key a backup has to be signed with, given to restore or verify
+pub fn verifying_key(signature: &Option<String>) -> Result<Option<VerifyingKey>, Error> {
+    signature.as_deref().map(VerifyingKey::load).transpose()
 }
 
 impl Opt {
     pub fn out(&self) -> &Option<PathBuf> {
         &self.out
     }
+    pub fn profile(&self) -> &Option<String> {
+        &self.profile
+    }
     pub fn dry_run(&self) -> bool {
         self.dry_run
     }
     pub fn quiet(&self) -> bool {
         self.quiet
     }
-    pub fn validate_paths(&self) -> io::Result<Vec<PathBuf>> {
-        let mut paths: Vec<PathBuf> = Vec::new();
-
-        // validate files from list
-        for path in &self.list {
-            if path.is_file() {
-                paths.push(path.to_path_buf());
-            } else if path.is_dir() {
-                let content =
-                    dir::get_dir_content(path).expect("Failed to get content of directory");
-                let mut content: Vec<PathBuf> = util::all_paths(content);
-                paths.append(&mut content);
-            } else {
-                FatalError::file_not_found(&path.display().to_string());
-            }
-        }
-        // validate files from file
-        match &self.file {
-            Some(file) => {
-                // file exists
-                if file.is_file() {
-                    let file = File::open(file)?;
-                    // line in file
-                    for line in BufReader::new(file).lines() {
-                        match line {
-                            Ok(path) => {
-                                // is this a file that exists
-                                let path = OsString::from(path);
-                                let path = PathBuf::from(path);
-                                if path.exists() {
-                                    paths.push(path);
-                                } else {
-                                    FatalError::file_not_found(&path.display().to_string());
-                                }
-                            }
-                            Err(e) => FatalError::error(&e.to_string()),
-                        }
-                    }
-                } else {
-                    FatalError::file_not_found(&file.display().to_string());
-                }
-            }
-            // ignore, -f flag was ommitted
-            None => (),
+    // Where the paths to back up come from
+    pub fn path_options(&self) -> PathOptions {
+        PathOptions {
+            list: self.list.clone(),
+            file: self.file.clone(),
+            exclude: self.exclude.clone(),
+            ignore_files: !self.no_ignore,
+            symlinks: self.symlinks(),
+            keep_going: self.keep_going,
         }
-        // exclude files from paths
-        for excluded in &self.exclude {
-            let mut index: i32 = 0;
-            while index < (paths.len() as i32) {
-                let path = paths.get(index as usize).unwrap();
-                let p: String = PathBuf::into_os_string(path.to_path_buf())
-                    .to_string_lossy()
-                    .to_string();
-                let e: String = PathBuf::into_os_string(excluded.to_path_buf())
-                    .to_string_lossy()
-                    .to_string();
-
-                if p.contains(&e) {
-                    paths.remove(index as usize);
-                    index -= 1;
-                }
-                index += 1;
-            }
-        }
-        Ok(paths)
     }
     pub fn verbose(&self) -> bool {
         self.verbose
     }
-    pub fn tar(&self) -> bool {
-        self.tar
+    pub fn format(&self) -> Result<Format, Error> {
+        match self.format {
+            Some(Format::Tar) => Ok(Format::Tar),
+            Some(_) if self.compression.is_some() => Err(Error::InvalidInput(
+                "--compression can only be used with tarballs".to_string(),
+            )),
+            Some(format) => Ok(format),
+            None if self.tar
+                || self.compression.is_some()
+                || self.level.is_some()
+                || self.encrypt.is_some() =>
+            {
+                Ok(Format::Tar)
+            }
+            None => Ok(Format::Dir),
+        }
+    }
+    pub fn compression(&self) -> Compression {
+        self.compression.unwrap_or(Compression::Xz)
+    }
+    pub fn level(&self) -> Option<u32> {
+        self.level
+    }
+    pub fn hash(&self) -> HashAlgorithm {
+        self.hash.unwrap_or(HashAlgorithm::Sha256)
+    }
+    pub fn incremental(&self) -> &Option<PathBuf> {
+        &self.incremental
+    }
+    pub fn on_existing(&self) -> OnExisting {
+        self.on_existing.unwrap_or(OnExisting::Fail)
+    }
+    pub fn symlinks(&self) -> Symlinks {
+        self.symlinks.unwrap_or_default()
+    }
+    pub fn keep_going(&self) -> bool {
+        self.keep_going
     }
-    pub fn new() -> Opt {
-        Opt::from_args()
+    pub fn jobs(&self) -> usize {
+        self.jobs.unwrap_or(1)
+    }
+    pub fn encryption(&self) -> Result<Option<Encryption>, Error> {
+        self.encrypt.as_deref().map(Encryption::parse).transpose()
+    }
+    pub fn keys(&self) -> Result<Keys, Error> {
+        Keys::load(&self.identity)
+    }
+    pub fn signing_key(&self) -> Result<Option<SigningKey>, Error> {
+        self.sign.as_deref().map(SigningKey::load).transpose()
+    }
+    pub fn scanner(&self) -> Result<Option<Scanner>, Error> {
+        let policy = match self.secrets {
+            Some(policy) => policy,
+            None if !self.secret_pattern.is_empty() => SecretPolicy::Warn,
+            None => return Ok(None),
+        };
+        Scanner::new(policy, &self.secret_pattern).map(Some)
+    }
+    pub fn repo(&self) -> &Option<PathBuf> {
+        &self.repo
+    }
+    pub fn cmd(&self) -> &Option<Command> {
+        &self.cmd
+    }
+    // Fill in everything not given on the command line from the selected profile
+    fn apply_profile(&mut self, name: &str) -> Result<(), Error> {
+        let path = match &self.config {
+            Some(path) => path.to_path_buf(),
+            None => config::default_path().unwrap_or_else(|| PathBuf::from("config.toml")),
+        };
+        let config = Config::load(&path)?;
+        let profile = config.profile(name, &path)?.clone();
+        // no shell sees the paths in a profile, they are expanded like the
+        // entries of a list file
+        let expand = |path: Option<PathBuf>| path.as_deref().map(expand_path).transpose();
+        let expand_all = |paths: Vec<PathBuf>| -> Result<Vec<PathBuf>, Error> {
+            paths.iter().map(|p| expand_path(p)).collect()
+        };
+        if self.list.is_empty() && self.file.is_none() {
+            self.list = expand_all(profile.include)?;
+            self.file = expand(profile.file)?;
+        }
+        if self.exclude.is_empty() {
+            self.exclude = expand_all(profile.exclude)?;
+        }
+        self.out = self.out.take().or(expand(profile.out)?);
+        self.repo = self.repo.take().or(expand(profile.repo)?);
+        if !self.tar {
+            self.format = self.format.or(profile.format);
+        }
+        self.compression = self.compression.or(profile.compression);
+        self.level = self.level.or(profile.level);
+        self.hash = self.hash.or(profile.hash);
+        self.on_existing = self.on_existing.or(profile.on_existing);
+        self.symlinks = self.symlinks.or(profile.symlinks);
+        self.keep_going |= profile.keep_going;
+        self.no_ignore |= profile.no_ignore;
+        self.jobs = self.jobs.or(profile.jobs);
+        self.encrypt = self.encrypt.take().or(profile.encrypt);
+        if self.identity.is_empty() {
+            self.identity = expand_all(profile.identity)?;
+        }
+        self.sign = self.sign.take().or(expand(profile.sign)?);
+        self.secrets = self.secrets.or(profile.secrets);
+        if self.secret_pattern.is_empty() {
+            self.secret_pattern = profile.secret_patterns;
+        }
+        Ok(())
+    }
+    pub fn new() -> Result<Opt, Error> {
+        let mut opt = Opt::from_args();
+        if let Some(name) = opt.profile.clone() {
+            opt.apply_profile(&name)?;
+        }
+        Ok(opt)
     }
 }
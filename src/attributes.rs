@@ -0,0 +1,184 @@
+use super::error::Error;
+use super::manifest::Manifest;
+use super::util;
+use filetime::FileTime;
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::io;
+use std::os::unix::fs::{self as unix_fs, MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+/// Ownership, access time and extended attributes of a file or directory.
+/// POSIX ACLs are kept by the kernel as the system.posix_acl_* attributes
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Attributes {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uid: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gid: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub atime: Option<i64>,
+    /// Extended attributes by name, their values hex encoded
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub xattrs: BTreeMap<String, String>,
+}
+
+impl Attributes {
+    // Attributes of `path`, following links unless `metadata` describes the
+    // link itself
+    pub(crate) fn read(path: &Path, metadata: &fs::Metadata) -> Result<Attributes, Error> {
+        let link = metadata.file_type().is_symlink();
+        let mut xattrs = BTreeMap::new();
+        if xattr::SUPPORTED_PLATFORM {
+            let names = if link {
+                xattr::list(path)
+            } else {
+                xattr::list_deref(path)
+            };
+            let names = match names {
+                Ok(names) => names.collect(),
+                Err(ref e) if unsupported(e) => Vec::new(),
+                Err(e) => return Err(Error::with_path(path)(e)),
+            };
+            for name in names {
+                let value = if link {
+                    xattr::get(path, &name)
+                } else {
+                    xattr::get_deref(path, &name)
+                };
+                if let Some(value) = value.map_err(Error::with_path(path))? {
+                    let value = value.iter().map(|b| format!("{:02x}", b)).collect();
+                    xattrs.insert(name.to_string_lossy().to_string(), value);
+                }
+            }
+        }
+        Ok(Attributes {
+            uid: Some(metadata.uid()),
+            gid: Some(metadata.gid()),
+            atime: Some(metadata.atime()),
+            xattrs,
+        })
+    }
+}
+
+// Directories of a manifest that don't exist under `dest` yet. Only those get
+// their recorded attributes, directories that were already there are left alone
+pub(crate) fn missing_directories<F>(manifest: &Manifest, dest: F) -> HashSet<PathBuf>
+where
+    F: Fn(&Path) -> PathBuf,
+{
+    manifest
+        .directories
+        .iter()
+        .filter(|dir| !dest(&dir.stored).exists())
+        .map(|dir| dir.stored.clone())
+        .collect()
+}
+
+// Give every file of a manifest that exists under `dest`, and the `created`
+// directories, the mode, times and attributes recorded for them. Directories
+// go last, deepest first, as filling them changes their mtime
+pub(crate) fn apply_manifest<F>(
+    manifest: &Manifest,
+    dest: F,
+    created: &HashSet<PathBuf>,
+    owner: bool,
+) -> Result<(), Error>
+where
+    F: Fn(&Path) -> PathBuf,
+{
+    for entry in &manifest.entries {
+        let path = dest(&entry.stored);
+        if entry.link.is_some() {
+            apply_link(&path, entry.mtime, &entry.attributes, owner)?;
+        } else if path.is_file() {
+            apply(&path, entry.mode, entry.mtime, &entry.attributes, owner)?;
+        }
+    }
+    let mut directories: Vec<_> = manifest
+        .directories
+        .iter()
+        .filter(|dir| created.contains(&dir.stored))
+        .collect();
+    directories.sort_by_key(|d| Reverse(d.stored.components().count()));
+    for dir in directories {
+        let path = dest(&dir.stored);
+        if path.is_dir() {
+            apply(&path, dir.mode, dir.mtime, &dir.attributes, owner)?;
+        }
+    }
+    Ok(())
+}
+
+// Give `path` the mode, times and attributes that were recorded for it.
+// Ownership is only changed when `owner` is set, since it takes root to hand
+// files to another user, and without it attributes the user may not set are
+// skipped as well
+fn apply(
+    path: &Path,
+    mode: u32,
+    mtime: i64,
+    attributes: &Attributes,
+    owner: bool,
+) -> Result<(), Error> {
+    if xattr::SUPPORTED_PLATFORM {
+        for (name, value) in &attributes.xattrs {
+            let value = decode(value).ok_or_else(|| {
+                Error::Manifest(format!(
+                    "Invalid value for attribute {} of {}",
+                    name,
+                    path.display()
+                ))
+            })?;
+            match xattr::set(path, name, &value) {
+                Err(ref e) if unsupported(e) => (),
+                Err(ref e) if !owner && e.kind() == io::ErrorKind::PermissionDenied => (),
+                result => result.map_err(Error::with_path(path))?,
+            }
+        }
+    }
+    // changing the owner can clear setuid bits, so it goes before the mode
+    if owner && (attributes.uid.is_some() || attributes.gid.is_some()) {
+        unix_fs::chown(path, attributes.uid, attributes.gid).map_err(Error::with_path(path))?;
+    }
+    fs::set_permissions(path, fs::Permissions::from_mode(mode)).map_err(Error::with_path(path))?;
+    let mtime = FileTime::from_unix_time(mtime, 0);
+    let atime = attributes
+        .atime
+        .map(|atime| FileTime::from_unix_time(atime, 0))
+        .unwrap_or(mtime);
+    filetime::set_file_times(path, atime, mtime).map_err(Error::with_path(path))
+}
+
+// Links have no mode of their own, they only get their owner and times back
+fn apply_link(path: &Path, mtime: i64, attributes: &Attributes, owner: bool) -> Result<(), Error> {
+    if !util::is_symlink(path) {
+        return Ok(());
+    }
+    if owner && (attributes.uid.is_some() || attributes.gid.is_some()) {
+        unix_fs::lchown(path, attributes.uid, attributes.gid).map_err(Error::with_path(path))?;
+    }
+    let mtime = FileTime::from_unix_time(mtime, 0);
+    let atime = attributes
+        .atime
+        .map(|atime| FileTime::from_unix_time(atime, 0))
+        .unwrap_or(mtime);
+    filetime::set_symlink_file_times(path, atime, mtime).map_err(Error::with_path(path))
+}
+
+// File systems without extended attributes just lose them, 95 is EOPNOTSUPP
+fn unsupported(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::Unsupported || e.raw_os_error() == Some(95)
+}
+
+fn decode(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
@@ -0,0 +1,322 @@
+use super::archive;
+use super::crypto::Keys;
+use super::error::Error;
+use super::manifest::{self, Manifest};
+use super::repo::Repository;
+use super::signature;
+use super::util;
+use chrono::{DateTime, Local, Utc};
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Where backups accumulate, a repository of snapshots or a directory of
+/// backups written with `--out` along with the keys to read encrypted ones
+pub enum Location {
+    Repository(Repository),
+    Directory(PathBuf, Keys),
+}
+
+/// One backup or snapshot along with its manifest
+pub struct Backup {
+    pub id: String,
+    pub time: DateTime<Utc>,
+    pub manifest: Manifest,
+}
+
+/// An archive named like a backup that can't be read, by id along with why
+pub type Unreadable = (String, Error);
+
+/// How many backups `prune` keeps. Each rule keeps the newest backup of that
+/// many distinct days, weeks or months, and a backup kept by any rule stays
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Retention {
+    pub last: usize,
+    pub daily: usize,
+    pub weekly: usize,
+    pub monthly: usize,
+}
+
+impl Location {
+    /// Every backup, oldest first, and the ids of archives that can't be read,
+    /// encrypted ones none of the keys can decrypt among them, with why
+    pub fn backups(&self) -> Result<(Vec<Backup>, Vec<Unreadable>), Error> {
+        let mut backups = Vec::new();
+        let mut unreadable = Vec::new();
+        match self {
+            Location::Repository(repo) => {
+                for id in repo.snapshots()? {
+                    let manifest = repo.snapshot(&id)?;
+                    backups.push(Backup::new(id, manifest)?);
+                }
+            }
+            Location::Directory(dir, keys) => {
+                let mut names = Vec::new();
+                for entry in fs::read_dir(dir).map_err(Error::with_path(dir))? {
+                    names.push(entry?.file_name());
+                }
+                names.sort();
+                for name in names {
+                    let path = dir.join(&name);
+                    let candidate = if path.is_dir() {
+                        path.join(manifest::MANIFEST_NAME).is_file()
+                    } else {
+                        archive::is_archive_name(&path)
+                    };
+                    if !candidate {
+                        continue;
+                    }
+                    let id = name.to_string_lossy().to_string();
+                    // a broken or foreign archive doesn't stop the rest
+                    match archive::read_manifest(&path, keys)
+                        .and_then(|m| m.map(|m| Backup::new(id.clone(), m)).transpose())
+                    {
+                        Ok(Some(backup)) => backups.push(backup),
+                        Ok(None) => (),
+                        Err(e) => unreadable.push((id, e)),
+                    }
+                }
+            }
+        }
+        backups.sort_by_key(|b| b.time);
+        Ok((backups, unreadable))
+    }
+
+    /// A single backup by id, `latest` picks the newest snapshot of a repository
+    pub fn backup(&self, id: &str) -> Result<Backup, Error> {
+        let manifest = match self {
+            Location::Repository(repo) => repo.snapshot(id)?,
+            Location::Directory(dir, keys) => {
+                if id.contains('/') {
+                    return Err(Error::InvalidInput(format!("Invalid backup id {}", id)));
+                }
+                let path = dir.join(id);
+                if !path.exists() {
+                    return Err(Error::NotFound(path));
+                }
+                archive::read_manifest(&path, keys)?
+                    .ok_or_else(|| Error::Manifest(format!("{} has no manifest", path.display())))?
+            }
+        };
+        Backup::new(id.to_string(), manifest)
+    }
+
+    // Delete backups, for a repository along with the objects only they used
+    fn remove(&self, backups: &[&Backup], print: &util::VerbosePrint) -> Result<(), Error> {
+        match self {
+            Location::Repository(repo) => {
+                let ids: Vec<String> = backups.iter().map(|b| b.id.clone()).collect();
+                let objects = repo.remove(&ids, print)?;
+                print.println(&format!("Removed {} unused objects", objects));
+            }
+            Location::Directory(dir, _) => {
+                for backup in backups {
+                    let path = dir.join(&backup.id);
+                    if path.is_dir() {
+                        fs::remove_dir_all(&path).map_err(Error::with_path(&path))?;
+                    } else {
+                        fs::remove_file(&path).map_err(Error::with_path(&path))?;
+                    }
+                    let signature = signature::signature_path(&path);
+                    if signature.exists() {
+                        fs::remove_file(&signature).map_err(Error::with_path(&signature))?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Canonical path of a backup, the way incremental backups point at it
+    fn path(&self, backup: &Backup) -> Option<PathBuf> {
+        match self {
+            Location::Repository(_) => None,
+            Location::Directory(dir, _) => Some(canonical(&dir.join(&backup.id))),
+        }
+    }
+}
+
+impl Backup {
+    fn new(id: String, manifest: Manifest) -> Result<Backup, Error> {
+        let time = DateTime::parse_from_rfc3339(&manifest.timestamp)
+            .map_err(|e| {
+                Error::Manifest(format!(
+                    "Invalid timestamp {} in {}: {}",
+                    manifest.timestamp, id, e
+                ))
+            })?
+            .with_timezone(&Utc);
+        Ok(Backup { id, time, manifest })
+    }
+    fn size(&self) -> u64 {
+        self.manifest.entries.iter().map(|e| e.size).sum()
+    }
+    fn date(&self) -> String {
+        self.time
+            .with_timezone(&Local)
+            .format("%Y-%m-%d %H:%M:%S")
+            .to_string()
+    }
+}
+
+impl Retention {
+    pub fn is_empty(&self) -> bool {
+        self.last == 0 && self.daily == 0 && self.weekly == 0 && self.monthly == 0
+    }
+    // Which of the backups, given newest first, are kept
+    fn keep(&self, backups: &[Backup]) -> Vec<bool> {
+        let mut keep: Vec<bool> = (0..backups.len()).map(|i| i < self.last).collect();
+        for (count, period) in [
+            (self.daily, "%Y-%m-%d"),
+            (self.weekly, "%G-W%V"),
+            (self.monthly, "%Y-%m"),
+        ]
+        .iter()
+        {
+            let mut seen = HashSet::new();
+            for (backup, keep) in backups.iter().zip(keep.iter_mut()) {
+                if seen.len() == *count {
+                    break;
+                }
+                let bucket = backup.time.with_timezone(&Local).format(period).to_string();
+                if seen.insert(bucket) {
+                    *keep = true;
+                }
+            }
+        }
+        keep
+    }
+}
+
+/// Print every backup with its date, file count and size
+pub fn list(location: &Location, print: &util::VerbosePrint) -> Result<(), Error> {
+    let (backups, unreadable) = location.backups()?;
+    if backups.is_empty() && unreadable.is_empty() {
+        print.println("No backups found");
+        return Ok(());
+    }
+    let width = backups
+        .iter()
+        .map(|b| &b.id)
+        .chain(unreadable.iter().map(|(id, _)| id))
+        .map(|id| id.len())
+        .max()
+        .unwrap_or(0);
+    for backup in &backups {
+        print.println(&format!(
+            "{:<width$}  {}  {:>5} files  {:>10}",
+            backup.id,
+            backup.date(),
+            backup.manifest.entries.len(),
+            util::human_size(backup.size()),
+            width = width
+        ));
+    }
+    for (id, error) in &unreadable {
+        let reason = match error {
+            Error::Encryption(_) => "encrypted, cannot be decrypted with the keys given",
+            _ => "cannot be read as a backup",
+        };
+        print.println(&format!("{:<width$}  {}", id, reason, width = width));
+        print.debug(&format!("\"{}\": {}", id, error));
+    }
+    Ok(())
+}
+
+/// Print where a backup was taken and every file in it
+pub fn show(location: &Location, id: &str, print: &util::VerbosePrint) -> Result<(), Error> {
+    let backup = location.backup(id)?;
+    print.println(&format!("Backup:    {}", backup.id));
+    print.println(&format!("Taken:     {}", backup.date()));
+    print.println(&format!("Host:      {}", backup.manifest.hostname));
+    if let Some(encryption) = &backup.manifest.encryption {
+        match encryption.recipients.is_empty() {
+            true => print.println(&format!("Encrypted: {}", encryption.method)),
+            false => print.println(&format!(
+                "Encrypted: {} to {}",
+                encryption.method,
+                encryption.recipients.join(", ")
+            )),
+        }
+    }
+    print.println(&format!(
+        "Files:     {} ({})",
+        backup.manifest.entries.len(),
+        util::human_size(backup.size())
+    ));
+    for entry in &backup.manifest.entries {
+        print.println(&format!(
+            "    {:>10}  {}",
+            util::human_size(entry.size),
+            util::original_path(&entry.stored).display()
+        ));
+    }
+    Ok(())
+}
+
+/// Delete every backup not kept by the retention rules. Backups that a kept
+/// incremental backup reads unchanged files from are always kept
+pub fn prune(
+    location: &Location,
+    retention: &Retention,
+    print: &util::VerbosePrint,
+    dry_run: bool,
+) -> Result<(), Error> {
+    if retention.is_empty() {
+        return Err(Error::InvalidInput(
+            "prune needs at least one of --keep-last, --keep-daily, --keep-weekly or --keep-monthly"
+                .to_string(),
+        ));
+    }
+    let (mut backups, unreadable) = location.backups()?;
+    backups.reverse();
+    // what can't be read can't be judged, so it is never removed
+    for (id, error) in &unreadable {
+        let reason = match error {
+            Error::Encryption(_) => "it is encrypted and cannot be decrypted with the keys given",
+            _ => "it cannot be read as a backup",
+        };
+        print.println(&format!("Keeping {}, {}", id, reason));
+        print.debug(&format!("\"{}\": {}", id, error));
+    }
+    let mut keep = retention.keep(&backups);
+    let needed: HashSet<PathBuf> = backups
+        .iter()
+        .zip(&keep)
+        .filter(|(_, keep)| **keep)
+        .flat_map(|(backup, _)| backup.manifest.entries.iter())
+        .filter_map(|entry| entry.base.as_ref().map(|base| canonical(base)))
+        .collect();
+    for (backup, keep) in backups.iter().zip(keep.iter_mut()) {
+        if !*keep && location.path(backup).is_some_and(|p| needed.contains(&p)) {
+            print.debug(&format!(
+                "Keeping \"{}\", a kept backup depends on it",
+                backup.id
+            ));
+            *keep = true;
+        }
+    }
+    let remove: Vec<&Backup> = backups
+        .iter()
+        .zip(&keep)
+        .filter(|(_, keep)| !**keep)
+        .map(|(backup, _)| backup)
+        .collect();
+    if dry_run {
+        print.println("Backups to be removed:");
+        for backup in &remove {
+            print.println(&format!("    {}", backup.id));
+        }
+        return Ok(());
+    }
+    print.println("Pruning");
+    for backup in &remove {
+        print.println(&format!("Removing {}", backup.id));
+    }
+    location.remove(&remove, print)
+}
+
+// Incremental backups record absolute paths, compare them without symlinks or `..`
+fn canonical(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
@@ -0,0 +1,61 @@
+use super::archive::{Compression, Format};
+use super::error::Error;
+use super::manifest::HashAlgorithm;
+use super::secrets::SecretPolicy;
+use super::util::{OnExisting, Symlinks};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Contents of config.toml, a set of named backup profiles
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub profile: BTreeMap<String, Profile>,
+}
+
+// Everything a profile can set, each value matches a command line flag
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Profile {
+    pub include: Vec<PathBuf>,
+    pub file: Option<PathBuf>,
+    pub exclude: Vec<PathBuf>,
+    pub out: Option<PathBuf>,
+    pub repo: Option<PathBuf>,
+    pub format: Option<Format>,
+    pub compression: Option<Compression>,
+    pub level: Option<u32>,
+    pub hash: Option<HashAlgorithm>,
+    pub on_existing: Option<OnExisting>,
+    pub symlinks: Option<Symlinks>,
+    pub keep_going: bool,
+    pub no_ignore: bool,
+    pub jobs: Option<usize>,
+    pub encrypt: Option<String>,
+    pub identity: Vec<PathBuf>,
+    pub sign: Option<PathBuf>,
+    pub secrets: Option<SecretPolicy>,
+    pub secret_patterns: Vec<String>,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Config, Error> {
+        let content = fs::read_to_string(path).map_err(Error::with_path(path))?;
+        toml::from_str(&content)
+            .map_err(|e| Error::Config(format!("Invalid config {}: {}", path.display(), e)))
+    }
+    // Settings of a profile by name
+    pub fn profile(&self, name: &str, path: &Path) -> Result<&Profile, Error> {
+        self.profile.get(name).ok_or_else(|| {
+            Error::Config(format!("Profile {} not found in {}", name, path.display()))
+        })
+    }
+}
+
+// ~/.config/confbk/config.toml, or wherever the platform keeps configs
+pub fn default_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|d| d.join("confbk").join("config.toml"))
+}
@@ -0,0 +1,230 @@
+use super::error::Error;
+use super::manifest::Encrypted;
+use age::secrecy::{ExposeSecret, SecretString};
+use age::stream::{StreamReader, StreamWriter};
+use age::{scrypt, x25519, DecryptError, Decryptor, Encryptor};
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Environment variable holding the passphrase for `--encrypt passphrase`,
+/// also tried when decrypting
+pub const PASSPHRASE_VAR: &str = "CONFBK_PASSPHRASE";
+
+/// Appended to the name of encrypted archives
+pub const EXTENSION: &str = ".age";
+
+// Every age file starts with this line
+const MAGIC: &[u8] = b"age-encryption.org/v1";
+
+/// What a backup is encrypted to, age X25519 recipients or a passphrase the
+/// key is derived from with scrypt
+pub enum Encryption {
+    Recipients(Vec<x25519::Recipient>),
+    Passphrase(SecretString),
+}
+
+impl Encryption {
+    /// Read what `--encrypt` was given: an age public key, a file of them one
+    /// per line, or `passphrase` for the one in CONFBK_PASSPHRASE
+    pub fn parse(value: &str) -> Result<Encryption, Error> {
+        if value == "passphrase" {
+            return passphrase().map(Encryption::Passphrase).ok_or_else(|| {
+                Error::InvalidInput(format!(
+                    "--encrypt passphrase reads the passphrase from {}, which is not set",
+                    PASSPHRASE_VAR
+                ))
+            });
+        }
+        if value.starts_with("age1") {
+            return Ok(Encryption::Recipients(vec![recipient(value, "--encrypt")?]));
+        }
+        Encryption::read(Path::new(value))
+    }
+
+    // Recipients listed in a file, blank lines and # comments are skipped
+    fn read(path: &Path) -> Result<Encryption, Error> {
+        let content = fs::read_to_string(path).map_err(Error::with_path(path))?;
+        let name = path.to_string_lossy();
+        let recipients = key_lines(&content)
+            .map(|line| recipient(line, &name))
+            .collect::<Result<Vec<_>, _>>()?;
+        if recipients.is_empty() {
+            return Err(Error::Encryption(format!(
+                "{} holds no age recipients",
+                path.display()
+            )));
+        }
+        Ok(Encryption::Recipients(recipients))
+    }
+
+    /// How the backup is encrypted, as recorded in its manifest
+    pub fn describe(&self) -> Encrypted {
+        match self {
+            Encryption::Recipients(recipients) => Encrypted {
+                method: "x25519".to_string(),
+                recipients: recipients.iter().map(|r| r.to_string()).collect(),
+            },
+            Encryption::Passphrase(_) => Encrypted {
+                method: "scrypt".to_string(),
+                recipients: Vec::new(),
+            },
+        }
+    }
+
+    // Wrap `output` so everything written to it is encrypted. The age header
+    // written first holds a stanza per recipient, or the scrypt salt and work
+    // factor. StreamWriter::finish has to be called once done
+    pub(crate) fn wrap<W: Write>(&self, output: W) -> Result<StreamWriter<W>, Error> {
+        let encryptor = match self {
+            Encryption::Recipients(recipients) => {
+                Encryptor::with_recipients(recipients.iter().map(|r| r as &dyn age::Recipient))
+                    .map_err(|e| Error::Encryption(e.to_string()))?
+            }
+            Encryption::Passphrase(passphrase) => {
+                Encryptor::with_user_passphrase(passphrase.clone())
+            }
+        };
+        Ok(encryptor.wrap_output(output)?)
+    }
+}
+
+/// What encrypted backups are decrypted with, age identities along with the
+/// passphrase in CONFBK_PASSPHRASE
+#[derive(Default)]
+pub struct Keys {
+    identities: Vec<x25519::Identity>,
+    passphrase: Option<SecretString>,
+}
+
+impl Keys {
+    /// Identities from age identity files, and the passphrase in
+    /// CONFBK_PASSPHRASE when it is set
+    pub fn load(files: &[PathBuf]) -> Result<Keys, Error> {
+        let mut identities = Vec::new();
+        for file in files {
+            let content = fs::read_to_string(file).map_err(Error::with_path(file))?;
+            for line in key_lines(&content) {
+                identities.push(x25519::Identity::from_str(line).map_err(|e| {
+                    Error::Encryption(format!("Invalid age identity in {}: {}", file.display(), e))
+                })?);
+            }
+        }
+        Ok(Keys {
+            identities,
+            passphrase: passphrase(),
+        })
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.identities.is_empty() && self.passphrase.is_none()
+    }
+
+    // Open a file for reading, decrypting it when it is encrypted
+    pub(crate) fn open(&self, path: &Path) -> Result<Box<dyn Read>, Error> {
+        let mut file = BufReader::new(File::open(path).map_err(Error::with_path(path))?);
+        if !file.fill_buf()?.starts_with(MAGIC) {
+            return Ok(Box::new(file));
+        }
+        Ok(Box::new(self.decrypt(file, path)?))
+    }
+
+    // Read the age header of `input` and unwrap its file key with one of the keys
+    fn decrypt<R: BufRead>(&self, input: R, path: &Path) -> Result<StreamReader<R>, Error> {
+        let failed = |e: DecryptError| match e {
+            DecryptError::NoMatchingKeys => Error::Encryption(format!(
+                "None of the keys given can decrypt {}, pass --identity or set {}",
+                path.display(),
+                PASSPHRASE_VAR
+            )),
+            e => Error::Encryption(format!("Cannot decrypt {}: {}", path.display(), e)),
+        };
+        let decryptor = Decryptor::new_buffered(input).map_err(failed)?;
+        let scrypt = self
+            .passphrase
+            .as_ref()
+            .map(|p| scrypt::Identity::new(p.clone()));
+        let identities = self
+            .identities
+            .iter()
+            .map(|i| i as &dyn age::Identity)
+            .chain(scrypt.iter().map(|i| i as &dyn age::Identity));
+        decryptor.decrypt(identities).map_err(failed)
+    }
+
+    // Decrypt the key of a repository, which its objects are encrypted to
+    pub(crate) fn unlock(&self, key: &Path) -> Result<Keys, Error> {
+        let mut secret = String::new();
+        self.open(key)?.read_to_string(&mut secret)?;
+        let identity = x25519::Identity::from_str(secret.trim()).map_err(|e| {
+            Error::Encryption(format!("Invalid repository key {}: {}", key.display(), e))
+        })?;
+        Ok(Keys {
+            identities: vec![identity],
+            passphrase: None,
+        })
+    }
+}
+
+// Make a key for a repository, saving it encrypted with `encryption` to `key`
+// and its public half to `public`
+pub(crate) fn new_key(
+    encryption: &Encryption,
+    key: &Path,
+    public: &Path,
+) -> Result<Encryption, Error> {
+    let identity = x25519::Identity::generate();
+    let file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(key)
+        .map_err(Error::with_path(key))?;
+    let mut output = encryption.wrap(file)?;
+    output.write_all(identity.to_string().expose_secret().as_bytes())?;
+    output.finish()?;
+    fs::write(public, format!("{}\n", identity.to_public())).map_err(Error::with_path(public))?;
+    Ok(Encryption::Recipients(vec![identity.to_public()]))
+}
+
+// Public key of a repository made by new_key
+pub(crate) fn public_key(public: &Path) -> Result<Encryption, Error> {
+    Encryption::read(public)
+}
+
+// Whether a file is age encrypted
+pub(crate) fn is_encrypted(path: &Path) -> Result<bool, Error> {
+    let mut magic = [0; 21];
+    let read = File::open(path)
+        .map_err(Error::with_path(path))?
+        .read(&mut magic)?;
+    Ok(magic[..read] == *MAGIC)
+}
+
+// Name of the archive `path` once encrypted
+pub(crate) fn encrypted_path(path: PathBuf) -> PathBuf {
+    let mut name = path.into_os_string();
+    name.push(EXTENSION);
+    PathBuf::from(name)
+}
+
+fn passphrase() -> Option<SecretString> {
+    env::var(PASSPHRASE_VAR)
+        .ok()
+        .filter(|p| !p.is_empty())
+        .map(SecretString::from)
+}
+
+fn recipient(value: &str, source: &str) -> Result<x25519::Recipient, Error> {
+    x25519::Recipient::from_str(value)
+        .map_err(|e| Error::Encryption(format!("Invalid age recipient in {}: {}", source, e)))
+}
+
+// Keys in an age recipients or identity file
+fn key_lines(content: &str) -> impl Iterator<Item = &str> {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+}
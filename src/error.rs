@@ -0,0 +1,119 @@
+use std::error;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Everything that can go wrong while backing up, restoring or verifying
+#[derive(Debug)]
+pub enum Error {
+    /// A path that was asked for does not exist
+    NotFound(PathBuf),
+    /// A backup of the same name is already there
+    Exists(PathBuf),
+    /// A path could not be read or written
+    PermissionDenied(PathBuf),
+    /// Any other I/O failure
+    Io(io::Error),
+    /// An archive could not be written or is malformed
+    Archive(String),
+    /// A glob could not be parsed or matched nothing
+    Pattern(String),
+    /// The config file or one of its profiles is invalid
+    Config(String),
+    /// A backup has no manifest or it could not be read
+    Manifest(String),
+    /// A repository, or a snapshot or object in it, is missing or malformed
+    Repository(String),
+    /// A backup could not be encrypted, or none of the keys given decrypt it
+    Encryption(String),
+    /// A backup is not signed, or not by the key it was checked against
+    Signature(String),
+    /// An option is out of range or combined with one it does not apply to
+    InvalidInput(String),
+    /// A backup does not match its manifest
+    Verification { backup: PathBuf, problems: usize },
+    /// A backup was written without some files, each listed with what went wrong
+    Incomplete(Vec<(PathBuf, String)>),
+}
+
+impl Error {
+    // Turn an I/O error about `path` into NotFound or PermissionDenied when it is one
+    pub(crate) fn with_path(path: &Path) -> impl Fn(io::Error) -> Error + '_ {
+        move |e| match e.kind() {
+            io::ErrorKind::NotFound => Error::NotFound(path.to_path_buf()),
+            io::ErrorKind::PermissionDenied => Error::PermissionDenied(path.to_path_buf()),
+            _ => Error::Io(e),
+        }
+    }
+    // What went wrong, without the path it happened to
+    pub(crate) fn reason(&self) -> String {
+        match self {
+            Error::NotFound(_) => "not found".to_string(),
+            Error::PermissionDenied(_) => "permission denied".to_string(),
+            Error::Io(e) => e.to_string(),
+            e => e.to_string().trim_start_matches("Error: ").to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::NotFound(path) => write!(f, "Error: File {} not found", path.display()),
+            Error::Exists(path) => write!(
+                f,
+                "Error: {} already exists, pick another --out or an --on-existing policy",
+                path.display()
+            ),
+            Error::PermissionDenied(path) => {
+                write!(f, "Error: Permission denied for {}", path.display())
+            }
+            Error::Io(e) => write!(f, "Error: {}", e),
+            Error::Archive(msg)
+            | Error::Pattern(msg)
+            | Error::Config(msg)
+            | Error::Manifest(msg)
+            | Error::Repository(msg)
+            | Error::Encryption(msg)
+            | Error::Signature(msg)
+            | Error::InvalidInput(msg) => write!(f, "Error: {}", msg),
+            Error::Verification { backup, problems } => write!(
+                f,
+                "Error: {} does not match its manifest ({} problems found)",
+                backup.display(),
+                problems
+            ),
+            Error::Incomplete(failed) => {
+                write!(f, "Error: {} files could not be backed up", failed.len())?;
+                for (path, reason) in failed {
+                    write!(f, "\n    {}: {}", path.display(), reason)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+impl From<zip::result::ZipError> for Error {
+    fn from(e: zip::result::ZipError) -> Error {
+        match e {
+            zip::result::ZipError::Io(e) => Error::Io(e),
+            e => Error::Archive(e.to_string()),
+        }
+    }
+}
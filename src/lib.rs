@@ -0,0 +1,59 @@
+//! Library behind the `confbk` command line tool.
+//!
+//! Paths are checked and expanded with [`validate_paths`], then written to a
+//! directory, tarball or zip with [`backup`]. Backups can be put back with
+//! [`restore`] and checked against their manifest with [`verify`]. A
+//! [`Repository`] keeps deduplicated snapshots instead. Every function
+//! reports failures through [`Error`] rather than exiting.
+
+extern crate age;
+extern crate blake3;
+extern crate bzip2;
+extern crate chrono;
+extern crate dirs;
+extern crate filetime;
+extern crate flate2;
+extern crate glob;
+extern crate globset;
+extern crate hostname;
+extern crate ignore;
+extern crate minisign;
+extern crate rayon;
+extern crate regex;
+extern crate serde;
+extern crate serde_json;
+extern crate sha2;
+extern crate tar;
+extern crate toml;
+extern crate xattr;
+extern crate xz2;
+extern crate zip;
+extern crate zstd;
+
+pub mod archive;
+mod attributes;
+pub mod catalog;
+pub mod config;
+mod crypto;
+mod error;
+pub mod manifest;
+mod paths;
+mod pattern;
+pub mod repo;
+mod restore;
+pub mod secrets;
+mod signature;
+pub mod util;
+mod verify;
+
+pub use attributes::Attributes;
+pub use catalog::{list, prune, show, Location, Retention};
+pub use crypto::{Encryption, Keys};
+pub use error::Error;
+pub use paths::{expand_path, validate_paths, PathOptions};
+pub use repo::Repository;
+pub use restore::{restore, RestoreOptions};
+pub use secrets::{Scanner, SecretPolicy};
+pub use signature::{SigningKey, VerifyingKey};
+pub use util::{backup, BackupOptions, VerboseLevel, VerbosePrint};
+pub use verify::verify;
@@ -1,43 +1,181 @@
-extern crate duct;
-extern crate fs_extra;
+extern crate confbk;
 extern crate structopt;
 
 mod args;
-mod util;
 
-use std::path::PathBuf;
+use confbk::util::{self, VerboseLevel, VerbosePrint};
+use confbk::{BackupOptions, Location, Repository, RestoreOptions, Retention};
+use std::path::{Path, PathBuf};
+use std::process;
+
+struct FatalError();
+
+impl FatalError {
+    fn error(msg: &str) -> ! {
+        eprintln!("{}", msg);
+        process::exit(1);
+    }
+}
+
+// Backups are managed in the repository given with --repo, or else in `dir`
+fn location(arguments: &args::Opt, dir: &Path) -> Result<Location, confbk::Error> {
+    match arguments.repo() {
+        Some(repo) => Ok(Location::Repository(
+            Repository::open(repo)?.with_keys(arguments.keys()?)?,
+        )),
+        None => Ok(Location::Directory(dir.to_path_buf(), arguments.keys()?)),
+    }
+}
 
 fn main() {
     // get arguments passed in
-    let arguments = args::Opt::new();
+    let arguments = args::Opt::new().unwrap_or_else(|e| FatalError::error(&e.to_string()));
     // Set verbosity
     let print = if arguments.quiet() {
-        util::VerbosePrint {
-            level: util::VerboseLevel::Off,
+        VerbosePrint {
+            level: VerboseLevel::Off,
         }
     } else if arguments.verbose() {
-        util::VerbosePrint {
-            level: util::VerboseLevel::On,
+        VerbosePrint {
+            level: VerboseLevel::On,
         }
     } else {
-        util::VerbosePrint {
-            level: util::VerboseLevel::Reg,
+        VerbosePrint {
+            level: VerboseLevel::Reg,
         }
     };
 
-    let paths = arguments.validate_paths().unwrap();
+    match arguments.cmd() {
+        Some(args::Command::Restore {
+            backup,
+            no_owner,
+            signature,
+        }) => {
+            print.debug(&format!("{:#?}", arguments));
+            let keys = arguments
+                .keys()
+                .unwrap_or_else(|e| FatalError::error(&e.to_string()));
+            let signature = args::verifying_key(signature)
+                .unwrap_or_else(|e| FatalError::error(&e.to_string()));
+            match arguments.repo() {
+                Some(repo) => {
+                    let options = RestoreOptions {
+                        dry_run: arguments.dry_run(),
+                        owner: !no_owner,
+                        keys: Default::default(),
+                        signature,
+                    };
+                    Repository::open(repo)
+                        .and_then(|repo| repo.with_keys(keys))
+                        .and_then(|repo| repo.restore(&backup.to_string_lossy(), &print, &options))
+                }
+                None => {
+                    let options = RestoreOptions {
+                        dry_run: arguments.dry_run(),
+                        owner: !no_owner,
+                        keys,
+                        signature,
+                    };
+                    confbk::restore(backup, &print, &options)
+                }
+            }
+            .unwrap_or_else(|e| FatalError::error(&e.to_string()));
+            return;
+        }
+        Some(args::Command::Verify { backup, signature }) => {
+            print.debug(&format!("{:#?}", arguments));
+            let keys = arguments
+                .keys()
+                .unwrap_or_else(|e| FatalError::error(&e.to_string()));
+            let signature = args::verifying_key(signature)
+                .unwrap_or_else(|e| FatalError::error(&e.to_string()));
+            match arguments.repo() {
+                Some(repo) => Repository::open(repo)
+                    .and_then(|repo| repo.with_keys(keys))
+                    .and_then(|repo| {
+                        repo.verify(&backup.to_string_lossy(), &print, signature.as_ref())
+                    }),
+                None => confbk::verify(backup, &print, &keys, signature.as_ref()),
+            }
+            .unwrap_or_else(|e| FatalError::error(&e.to_string()));
+            return;
+        }
+        Some(args::Command::List { dir }) => {
+            print.debug(&format!("{:#?}", arguments));
+            location(&arguments, dir)
+                .and_then(|location| confbk::list(&location, &print))
+                .unwrap_or_else(|e| FatalError::error(&e.to_string()));
+            return;
+        }
+        Some(args::Command::Show { id, dir }) => {
+            print.debug(&format!("{:#?}", arguments));
+            location(&arguments, dir)
+                .and_then(|location| confbk::show(&location, id, &print))
+                .unwrap_or_else(|e| FatalError::error(&e.to_string()));
+            return;
+        }
+        Some(args::Command::Prune {
+            keep_last,
+            keep_daily,
+            keep_weekly,
+            keep_monthly,
+            dir,
+        }) => {
+            print.debug(&format!("{:#?}", arguments));
+            let retention = Retention {
+                last: keep_last.unwrap_or(0),
+                daily: keep_daily.unwrap_or(0),
+                weekly: keep_weekly.unwrap_or(0),
+                monthly: keep_monthly.unwrap_or(0),
+            };
+            location(&arguments, dir)
+                .and_then(|location| {
+                    confbk::prune(&location, &retention, &print, arguments.dry_run())
+                })
+                .unwrap_or_else(|e| FatalError::error(&e.to_string()));
+            return;
+        }
+        None => (),
+    }
+
+    let paths = confbk::validate_paths(&arguments.path_options())
+        .unwrap_or_else(|e| FatalError::error(&e.to_string()));
     print.debug(&format!("{:#?}", arguments));
-    let path = PathBuf::from("confbk_backup");
-    let out_file = match arguments.out() {
-        Some(s) => s,
-        None => &path,
+    let format = arguments
+        .format()
+        .unwrap_or_else(|e| FatalError::error(&e.to_string()));
+    let template = match arguments.out() {
+        Some(out) => out.to_path_buf(),
+        None => PathBuf::from(util::DEFAULT_OUT),
+    };
+    let options = BackupOptions {
+        dry_run: arguments.dry_run(),
+        format,
+        compression: arguments.compression(),
+        level: arguments.level(),
+        hash: arguments.hash(),
+        incremental: arguments.incremental().clone(),
+        repo: arguments.repo().clone(),
+        on_existing: arguments.on_existing(),
+        symlinks: arguments.symlinks(),
+        keep_going: arguments.keep_going(),
+        jobs: arguments.jobs(),
+        encrypt: arguments
+            .encryption()
+            .unwrap_or_else(|e| FatalError::error(&e.to_string())),
+        keys: arguments
+            .keys()
+            .unwrap_or_else(|e| FatalError::error(&e.to_string())),
+        sign: arguments
+            .signing_key()
+            .unwrap_or_else(|e| FatalError::error(&e.to_string())),
+        secrets: arguments
+            .scanner()
+            .unwrap_or_else(|e| FatalError::error(&e.to_string())),
     };
-    util::backup(
-        &paths,
-        &print,
-        &out_file,
-        arguments.dry_run(),
-        arguments.tar(),
-    )
-    .unwrap_or_else(|e| util::FatalError::error(&e.to_string()));
+    let out_file = util::out_path(&template, arguments.profile().as_deref(), &options)
+        .unwrap_or_else(|e| FatalError::error(&e.to_string()));
+    print.debug(&format!("Backing up to \"{}\"", out_file.display()));
+    confbk::backup(&paths, &print, &out_file, &options)
+        .unwrap_or_else(|e| FatalError::error(&e.to_string()));
 }
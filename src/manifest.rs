@@ -0,0 +1,376 @@
+use super::attributes::Attributes;
+use super::error::Error;
+use super::util::{self, Failures, Symlinks};
+use chrono::{SecondsFormat, Utc};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{hash_map, BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Name of the manifest at the root of every backup
+pub const MANIFEST_NAME: &str = ".confbk-manifest.json";
+
+// Record of everything captured by one backup
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Manifest {
+    pub version: String,
+    pub hostname: String,
+    pub timestamp: String,
+    pub hash_algorithm: HashAlgorithm,
+    /// How symbolic links were backed up
+    #[serde(default)]
+    pub symlinks: Symlinks,
+    /// How the backup is encrypted, the age header holds the rest
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encryption: Option<Encrypted>,
+    /// Backup this one is incremental against
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub previous: Option<PathBuf>,
+    pub entries: Vec<Entry>,
+    /// Directories leading to the files that were backed up
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub directories: Vec<Directory>,
+    /// Files in the previous backup that no longer exist, by location inside the backup
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub deleted: Vec<PathBuf>,
+    /// Redacted contents backed up in place of files by source, only held
+    /// while the backup is written
+    #[serde(skip)]
+    pub(crate) redacted: HashMap<PathBuf, Vec<u8>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Entry {
+    /// Path as it was given to confbk
+    pub source: PathBuf,
+    /// Absolute location the file was read from
+    pub path: PathBuf,
+    /// Location inside the backup, relative to its root
+    pub stored: PathBuf,
+    pub size: u64,
+    pub mode: u32,
+    pub mtime: i64,
+    pub file_type: FileType,
+    pub hash: String,
+    /// Earlier backup holding the file when it has not changed since
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub base: Option<PathBuf>,
+    /// Where a preserved symbolic link points, its hash is that of the target path
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub link: Option<PathBuf>,
+    /// Earlier entry that is a hard link to the same file, the contents are
+    /// only stored for that one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hardlink: Option<PathBuf>,
+    /// Rules whose secrets were replaced by placeholders in the backed up copy
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub redacted: Vec<String>,
+    #[serde(flatten)]
+    pub attributes: Attributes,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Directory {
+    /// Location inside the backup, relative to its root
+    pub stored: PathBuf,
+    pub mode: u32,
+    pub mtime: i64,
+    #[serde(flatten)]
+    pub attributes: Attributes,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Encrypted {
+    /// x25519 for age recipients, scrypt for a passphrase
+    pub method: String,
+    /// Public keys the backup is encrypted to
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub recipients: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FileType {
+    File,
+    Dir,
+    Symlink,
+}
+
+// Hash used for the content of every entry
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HashAlgorithm {
+    Sha256,
+    Blake3,
+}
+
+impl FromStr for HashAlgorithm {
+    type Err = String;
+    fn from_str(s: &str) -> Result<HashAlgorithm, String> {
+        match s {
+            "sha256" => Ok(HashAlgorithm::Sha256),
+            "blake3" => Ok(HashAlgorithm::Blake3),
+            _ => Err(format!(
+                "Unknown hash {}, expected one of sha256 or blake3",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HashAlgorithm::Sha256 => write!(f, "sha256"),
+            HashAlgorithm::Blake3 => write!(f, "blake3"),
+        }
+    }
+}
+
+impl Manifest {
+    // Describe every file that will be backed up, directories are listed file by file
+    pub(crate) fn new(
+        paths: &[PathBuf],
+        hash: HashAlgorithm,
+        symlinks: Symlinks,
+        failures: &mut Failures,
+    ) -> Result<Manifest, Error> {
+        let mut files = Vec::new();
+        for path in paths {
+            if symlinks != Symlinks::Follow && util::is_symlink(path) {
+                if symlinks == Symlinks::Preserve {
+                    files.push(path.to_path_buf());
+                }
+            } else if path.is_dir() {
+                match util::dir_files(path, false, symlinks, false) {
+                    Ok(mut found) => files.append(&mut found),
+                    Err(e) => failures.add(path, e)?,
+                }
+            } else {
+                files.push(path.to_path_buf());
+            }
+        }
+        // hashed on the worker pool, failures are still reported in order
+        let read: Vec<Result<Entry, Error>> = files
+            .par_iter()
+            .map(|file| Entry::new(file, hash, symlinks))
+            .collect();
+        let mut entries = Vec::new();
+        for (file, entry) in files.iter().zip(read) {
+            match entry {
+                Ok(entry) => entries.push(entry),
+                Err(e) => failures.add(file, e)?,
+            }
+        }
+        link_entries(&mut entries)?;
+        // every directory leading to a file, so restores can recreate them as they were
+        let mut directories = BTreeSet::new();
+        for entry in &entries {
+            for dir in entry.source.ancestors().skip(1) {
+                if dir.as_os_str().is_empty() || dir == Path::new("/") {
+                    break;
+                }
+                if !directories.insert(dir.to_path_buf()) {
+                    break;
+                }
+            }
+        }
+        Ok(Manifest {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            hostname: hostname::get()?.to_string_lossy().to_string(),
+            timestamp: Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+            hash_algorithm: hash,
+            symlinks,
+            encryption: None,
+            previous: None,
+            entries,
+            directories: directories
+                .iter()
+                .map(|dir| Directory::new(dir))
+                .collect::<Result<_, _>>()?,
+            deleted: Vec::new(),
+            redacted: HashMap::new(),
+        })
+    }
+    // Make this an incremental backup against `previous`, found at `path`.
+    // Files that are unchanged point at the backup that holds them and every
+    // file that has gone since is recorded as deleted
+    pub(crate) fn incremental(&mut self, previous: &Manifest, path: &Path) {
+        let mut earlier: BTreeMap<&Path, &Entry> = previous
+            .entries
+            .iter()
+            .map(|e| (e.stored.as_path(), e))
+            .collect();
+        for entry in &mut self.entries {
+            if let Some(old) = earlier.remove(entry.stored.as_path()) {
+                if previous.hash_algorithm == self.hash_algorithm && entry.same(old) {
+                    entry.base = Some(old.base.clone().unwrap_or_else(|| path.to_path_buf()));
+                }
+            }
+        }
+        // a new link to an unchanged file has nothing in this backup to link
+        // to, so it is stored in full
+        let unchanged: BTreeSet<PathBuf> = self
+            .entries
+            .iter()
+            .filter(|e| e.base.is_some())
+            .map(|e| e.stored.clone())
+            .collect();
+        for entry in &mut self.entries {
+            if entry.base.is_none()
+                && entry
+                    .hardlink
+                    .as_ref()
+                    .is_some_and(|h| unchanged.contains(h))
+            {
+                entry.hardlink = None;
+            }
+        }
+        self.previous = Some(path.to_path_buf());
+        self.deleted = earlier.keys().map(|p| p.to_path_buf()).collect();
+    }
+    // Source of every hard link to an earlier entry, along with where that entry is stored
+    pub(crate) fn hardlinks(&self) -> HashMap<&Path, &Path> {
+        self.entries
+            .iter()
+            .filter_map(|e| Some((e.source.as_path(), e.hardlink.as_deref()?)))
+            .collect()
+    }
+    pub fn to_json(&self) -> Result<Vec<u8>, Error> {
+        serde_json::to_vec_pretty(self).map_err(|e| Error::Manifest(e.to_string()))
+    }
+    pub fn from_json<R: Read>(reader: R) -> Result<Manifest, Error> {
+        serde_json::from_reader(reader)
+            .map_err(|e| Error::Manifest(format!("Invalid manifest: {}", e)))
+    }
+    pub fn write(&self, path: &Path) -> Result<(), Error> {
+        fs::write(path, self.to_json()?).map_err(Error::with_path(path))
+    }
+}
+
+impl Entry {
+    fn new(path: &Path, hash: HashAlgorithm, symlinks: Symlinks) -> Result<Entry, Error> {
+        let metadata = fs::symlink_metadata(path).map_err(Error::with_path(path))?;
+        let file_type = if metadata.file_type().is_symlink() {
+            FileType::Symlink
+        } else if metadata.is_dir() {
+            FileType::Dir
+        } else {
+            FileType::File
+        };
+        if file_type == FileType::Symlink && symlinks == Symlinks::Preserve {
+            return Entry::link(path, hash, &metadata);
+        }
+        // links are followed when copying, so describe what they point to
+        let metadata = fs::metadata(path).map_err(Error::with_path(path))?;
+        // read before hashing, which may update the access time
+        let attributes = Attributes::read(path, &metadata)?;
+        Ok(Entry {
+            source: path.to_path_buf(),
+            path: std::path::absolute(path)?,
+            stored: util::backup_path(path),
+            size: metadata.len(),
+            mode: metadata.permissions().mode(),
+            mtime: metadata.mtime(),
+            file_type,
+            hash: hash_reader(&mut File::open(path).map_err(Error::with_path(path))?, hash)?,
+            base: None,
+            link: None,
+            hardlink: None,
+            redacted: Vec::new(),
+            attributes,
+        })
+    }
+    // Describe a link that is backed up as a link rather than what it points to
+    fn link(path: &Path, hash: HashAlgorithm, metadata: &fs::Metadata) -> Result<Entry, Error> {
+        let target = fs::read_link(path).map_err(Error::with_path(path))?;
+        let bytes = target.as_os_str().as_bytes();
+        Ok(Entry {
+            source: path.to_path_buf(),
+            path: std::path::absolute(path)?,
+            stored: util::backup_path(path),
+            size: bytes.len() as u64,
+            mode: metadata.permissions().mode(),
+            mtime: metadata.mtime(),
+            file_type: FileType::Symlink,
+            hash: hash_reader(&mut &bytes[..], hash)?,
+            base: None,
+            link: Some(target),
+            hardlink: None,
+            redacted: Vec::new(),
+            attributes: Attributes::read(path, metadata)?,
+        })
+    }
+    // Whether the file looks the same as when `other` was recorded
+    fn same(&self, other: &Entry) -> bool {
+        self.size == other.size
+            && self.mtime == other.mtime
+            && self.file_type == other.file_type
+            && self.hash == other.hash
+    }
+}
+
+impl Directory {
+    fn new(path: &Path) -> Result<Directory, Error> {
+        let metadata = fs::metadata(path).map_err(Error::with_path(path))?;
+        Ok(Directory {
+            stored: util::backup_path(path),
+            mode: metadata.permissions().mode(),
+            mtime: metadata.mtime(),
+            attributes: Attributes::read(path, &metadata)?,
+        })
+    }
+}
+
+// Point every file that shares its inode with an earlier entry at that entry
+fn link_entries(entries: &mut [Entry]) -> Result<(), Error> {
+    let mut first: HashMap<(u64, u64), PathBuf> = HashMap::new();
+    for entry in entries.iter_mut() {
+        if entry.file_type != FileType::File {
+            continue;
+        }
+        // links that were followed are not hard links
+        let metadata =
+            fs::symlink_metadata(&entry.source).map_err(Error::with_path(&entry.source))?;
+        if !metadata.is_file() || metadata.nlink() < 2 {
+            continue;
+        }
+        match first.entry((metadata.dev(), metadata.ino())) {
+            hash_map::Entry::Occupied(stored) => entry.hardlink = Some(stored.get().clone()),
+            hash_map::Entry::Vacant(slot) => {
+                slot.insert(entry.stored.clone());
+            }
+        }
+    }
+    Ok(())
+}
+
+// Hex encoded hash of everything left in a reader
+pub fn hash_reader<R: Read + ?Sized>(
+    reader: &mut R,
+    algorithm: HashAlgorithm,
+) -> io::Result<String> {
+    match algorithm {
+        HashAlgorithm::Sha256 => {
+            let mut hasher = Sha256::new();
+            io::copy(reader, &mut hasher)?;
+            Ok(hasher
+                .finalize()
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect())
+        }
+        HashAlgorithm::Blake3 => {
+            let mut hasher = blake3::Hasher::new();
+            io::copy(reader, &mut hasher)?;
+            Ok(hasher.finalize().to_hex().to_string())
+        }
+    }
+}
@@ -0,0 +1,275 @@
+use super::error::Error;
+use super::pattern::{self, Pattern};
+use super::util::{self, Symlinks};
+use std::collections::HashSet;
+use std::env;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+
+/// Where the paths to back up come from
+#[derive(Debug, Default)]
+pub struct PathOptions {
+    /// Files, directories or globs to back up, taken as they are
+    pub list: Vec<PathBuf>,
+    /// A list file with an entry like the ones in `list` per line, see
+    /// [`validate_paths`] for its syntax and [`expand_path`] for what is
+    /// filled in
+    pub file: Option<PathBuf>,
+    /// Globs of paths to leave out
+    pub exclude: Vec<PathBuf>,
+    /// Skip files matched by .gitignore, .ignore and .confbkignore in directories
+    pub ignore_files: bool,
+    /// Whether links are kept as links, followed or left out
+    pub symlinks: Symlinks,
+    /// List directories that can't be read instead of failing, so the backup
+    /// reports them along with every other file it could not read
+    pub keep_going: bool,
+}
+
+/// Check every path exists and turn directories and globs into the files they hold.
+///
+/// In the list file blank lines and lines starting with `#` are skipped, a
+/// `?` in front of an entry skips it when nothing is there, and
+/// `@include <file>` reads another list file, relative to the one including it
+pub fn validate_paths(options: &PathOptions) -> Result<Vec<PathBuf>, Error> {
+    let mut paths: Vec<PathBuf> = Vec::new();
+    // negated entries in the list or file exclude what they match
+    let mut excludes: Vec<Pattern> = Vec::new();
+
+    // validate files from list
+    for path in &options.list {
+        add_path(path, false, options, &mut paths, &mut excludes)?;
+    }
+    // validate files from file
+    if let Some(file) = &options.file {
+        read_list(file, &mut Vec::new(), options, &mut paths, &mut excludes)?;
+    }
+    // exclude files from paths
+    for excluded in &options.exclude {
+        excludes.push(Pattern::new(&excluded.to_string_lossy())?);
+    }
+    paths.retain(|path| !pattern::excluded(&excludes, path));
+    // globs and directories can overlap, keep the first occurrence
+    let mut seen = HashSet::new();
+    paths.retain(|path| seen.insert(path.clone()));
+    Ok(paths)
+}
+
+// Add every entry of a list file and the files it includes. `including` holds
+// the files that led to this one, by canonical path and as they were named, so
+// a file including itself is caught
+fn read_list(
+    file: &Path,
+    including: &mut Vec<(PathBuf, PathBuf)>,
+    options: &PathOptions,
+    paths: &mut Vec<PathBuf>,
+    excludes: &mut Vec<Pattern>,
+) -> Result<(), Error> {
+    let canonical = file.canonicalize().map_err(Error::with_path(file))?;
+    if let Some(start) = including.iter().position(|(c, _)| *c == canonical) {
+        let chain: Vec<String> = including[start..]
+            .iter()
+            .map(|(_, named)| named.display().to_string())
+            .chain(Some(file.display().to_string()))
+            .collect();
+        return Err(Error::InvalidInput(format!(
+            "List files include each other: {}",
+            chain.join(" -> ")
+        )));
+    }
+    including.push((canonical, file.to_path_buf()));
+    let reader = BufReader::new(File::open(file).map_err(Error::with_path(file))?);
+    for (number, line) in reader.lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some(directive) = line.strip_prefix('@') {
+            let included = match directive.strip_prefix("include") {
+                Some(included) if included.starts_with(char::is_whitespace) => included.trim(),
+                _ => {
+                    return Err(Error::InvalidInput(format!(
+                        "Unknown directive {} on line {} of {}, expected @include",
+                        line,
+                        number + 1,
+                        file.display()
+                    )))
+                }
+            };
+            let included = expand_path(Path::new(included))?;
+            let included = file.parent().unwrap_or(Path::new("")).join(included);
+            read_list(&included, including, options, paths, excludes)?;
+            continue;
+        }
+        let (optional, entry) = match line.strip_prefix('?') {
+            Some(entry) => (true, entry),
+            None => (false, line),
+        };
+        add_path(
+            &expand_path(Path::new(entry))?,
+            optional,
+            options,
+            paths,
+            excludes,
+        )?;
+    }
+    including.pop();
+    Ok(())
+}
+
+// Add a path from the list or file, expanding globs and directories.
+// Paths starting with `!` are turned into excludes instead. Optional paths
+// are skipped when nothing is there
+fn add_path(
+    path: &Path,
+    optional: bool,
+    options: &PathOptions,
+    paths: &mut Vec<PathBuf>,
+    excludes: &mut Vec<Pattern>,
+) -> Result<(), Error> {
+    if let Some(pattern) = path.to_string_lossy().strip_prefix('!') {
+        excludes.push(Pattern::new(pattern)?);
+        return Ok(());
+    }
+    let matched = if pattern::is_glob(path) {
+        let matched = pattern::expand(path)?;
+        if matched.is_empty() {
+            if optional {
+                return Ok(());
+            }
+            return Err(Error::Pattern(format!("No files match {}", path.display())));
+        }
+        matched
+    } else {
+        vec![path.to_path_buf()]
+    };
+    for path in matched {
+        if options.symlinks != Symlinks::Follow && util::is_symlink(&path) {
+            if options.symlinks == Symlinks::Preserve {
+                paths.push(path);
+            }
+        } else if path.is_file() {
+            paths.push(path);
+        } else if path.is_dir() {
+            paths.append(&mut util::dir_files(
+                &path,
+                options.ignore_files,
+                options.symlinks,
+                options.keep_going,
+            )?);
+        } else if !optional {
+            return Err(Error::NotFound(path));
+        }
+    }
+    Ok(())
+}
+
+/// Fill in a path from a list so list files work for every user. A leading
+/// `~` is the home directory, `$VAR` and `${VAR}` the value of an environment
+/// variable and `${VAR:-default}` the default when it is unset or empty.
+/// `{config}`, `{data}` and `{home}` are the user's XDG config and data
+/// directories and home directory. `$$` is a literal `$`
+pub fn expand_path(path: &Path) -> Result<PathBuf, Error> {
+    let text = match path.to_str() {
+        Some(text) if text.contains(['~', '$', '{']) => text,
+        _ => return Ok(path.to_path_buf()),
+    };
+    // a ! in front of an exclude is kept
+    match text.strip_prefix('!') {
+        Some(rest) => Ok(PathBuf::from(format!("!{}", expand(rest, text)?))),
+        None => Ok(PathBuf::from(expand(text, text)?)),
+    }
+}
+
+// Expand `text`, part of the list entry `path`. Defaults are expanded too
+fn expand(text: &str, path: &str) -> Result<String, Error> {
+    let mut expanded = String::new();
+    let mut rest = text;
+    if rest == "~" || rest.starts_with("~/") {
+        expanded.push_str(&home(path)?);
+        rest = &rest[1..];
+    }
+    while let Some(start) = rest.find(['$', '{']) {
+        expanded.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let (value, used) = if rest.starts_with("$$") {
+            ("$".to_string(), 2)
+        } else if rest.starts_with("${") {
+            let end = closing_brace(rest)
+                .ok_or_else(|| Error::InvalidInput(format!("Unclosed ${{ in {}", path)))?;
+            let (name, default) = match rest[2..end].find(":-") {
+                Some(split) => (&rest[2..2 + split], Some(&rest[2 + split + 2..end])),
+                None => (&rest[2..end], None),
+            };
+            (variable(name, default, path)?, end + 1)
+        } else if let Some(after) = rest.strip_prefix('$') {
+            let name = match after.find(|c: char| !c.is_ascii_alphanumeric() && c != '_') {
+                Some(end) => &after[..end],
+                None => after,
+            };
+            if name.is_empty() {
+                ("$".to_string(), 1)
+            } else {
+                (variable(name, None, path)?, 1 + name.len())
+            }
+        } else {
+            // braces that aren't a placeholder are part of the name
+            let end = rest.find('}').unwrap_or(0);
+            let dir = match &rest[..end + 1] {
+                "{config}" => dirs::config_dir(),
+                "{data}" => dirs::data_dir(),
+                "{home}" => dirs::home_dir(),
+                _ => {
+                    expanded.push('{');
+                    rest = &rest[1..];
+                    continue;
+                }
+            };
+            let dir = dir.ok_or_else(|| {
+                Error::InvalidInput(format!("Cannot find {} for {}", &rest[..end + 1], path))
+            })?;
+            (dir.to_string_lossy().to_string(), end + 1)
+        };
+        expanded.push_str(&value);
+        rest = &rest[used..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+// Index of the } closing the ${ that `text` starts with, skipping any pairs
+// of braces inside it
+fn closing_brace(text: &str) -> Option<usize> {
+    let mut depth = 0;
+    for (i, c) in text.char_indices().skip(1) {
+        match c {
+            '{' => depth += 1,
+            '}' if depth == 1 => return Some(i),
+            '}' => depth -= 1,
+            _ => (),
+        }
+    }
+    None
+}
+
+// Value of an environment variable used in `path`, or `default` when it is
+// unset or empty
+fn variable(name: &str, default: Option<&str>, path: &str) -> Result<String, Error> {
+    match (env::var(name), default) {
+        (Ok(value), Some(_)) if !value.is_empty() => Ok(value),
+        (Ok(value), None) => Ok(value),
+        (_, Some(default)) => expand(default, path),
+        (Err(_), None) => Err(Error::InvalidInput(format!(
+            "Environment variable {} in {} is not set",
+            name, path
+        ))),
+    }
+}
+
+fn home(path: &str) -> Result<String, Error> {
+    dirs::home_dir()
+        .map(|home| home.to_string_lossy().to_string())
+        .ok_or_else(|| Error::InvalidInput(format!("Cannot find the home directory for {}", path)))
+}
@@ -0,0 +1,81 @@
+use super::error::Error;
+use globset::{GlobBuilder, GlobMatcher};
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+// A glob used to exclude paths, or to keep them when negated with a leading `!`.
+// Patterns without a `/` match any single component of a path, the others are
+// matched against the whole path and every directory leading up to it
+#[derive(Debug)]
+pub struct Pattern {
+    glob: GlobMatcher,
+    negated: bool,
+    anchored: bool,
+}
+
+impl Pattern {
+    pub fn new(pattern: &str) -> Result<Pattern, Error> {
+        let (negated, pattern) = match pattern.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            None => (false, pattern),
+        };
+        let pattern = pattern.trim_start_matches("./").trim_end_matches('/');
+        let glob = GlobBuilder::new(pattern)
+            .literal_separator(true)
+            .build()
+            .map_err(|e| Error::Pattern(format!("Invalid pattern {}: {}", pattern, e)))?
+            .compile_matcher();
+        Ok(Pattern {
+            glob,
+            negated,
+            anchored: pattern.contains('/'),
+        })
+    }
+    fn matches(&self, path: &Path) -> bool {
+        let path: PathBuf = path
+            .components()
+            .filter(|c| *c != Component::CurDir)
+            .collect();
+        if self.anchored {
+            path.ancestors()
+                .filter(|p| !p.as_os_str().is_empty())
+                .any(|p| self.glob.is_match(p))
+        } else {
+            path.components()
+                .any(|c| self.glob.is_match(Path::new(c.as_os_str())))
+        }
+    }
+}
+
+// Whether a path is excluded, the last pattern that matches it decides
+pub fn excluded(patterns: &[Pattern], path: &Path) -> bool {
+    patterns
+        .iter()
+        .rev()
+        .find(|p| p.matches(path))
+        .is_some_and(|p| !p.negated)
+}
+
+// Whether a path given to confbk should be expanded as a glob
+pub fn is_glob(path: &Path) -> bool {
+    path.to_string_lossy()
+        .chars()
+        .any(|c| matches!(c, '*' | '?' | '['))
+}
+
+// Every existing path a glob matches, in sorted order
+pub fn expand(pattern: &Path) -> Result<Vec<PathBuf>, Error> {
+    let options = glob::MatchOptions {
+        case_sensitive: true,
+        require_literal_separator: true,
+        require_literal_leading_dot: false,
+    };
+    let pattern = pattern.to_string_lossy();
+    let mut paths = Vec::new();
+    let matches = glob::glob_with(&pattern, options)
+        .map_err(|e| Error::Pattern(format!("Invalid pattern {}: {}", pattern, e)))?;
+    for path in matches {
+        paths.push(path.map_err(io::Error::from)?);
+    }
+    Ok(paths)
+}
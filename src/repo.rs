@@ -0,0 +1,485 @@
+use super::attributes;
+use super::crypto::{self, Encryption, Keys};
+use super::error::Error;
+use super::manifest::{self, Manifest};
+use super::restore::{self, RestoreOptions};
+use super::signature::{self, VerifyingKey};
+use super::util::{self, BackupOptions, Failures};
+use chrono::Utc;
+use rayon::prelude::*;
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+const OBJECTS: &str = "objects";
+const SNAPSHOTS: &str = "snapshots";
+const LOCK: &str = "lock";
+const KEY: &str = "key.age";
+const PUBLIC_KEY: &str = "key.pub";
+
+/// Snapshot id standing for the most recent snapshot of a repository
+pub const LATEST: &str = "latest";
+
+/// A directory of deduplicated snapshots. File contents are stored once under
+/// `objects/` keyed by their hash, and every backup adds an index of what it
+/// captured to `snapshots/`, named after its id. In an encrypted repository
+/// objects and snapshots are encrypted to a key of its own, kept in `key.age`
+/// encrypted with what `--encrypt` was given the first time
+pub struct Repository {
+    root: PathBuf,
+    keys: Keys,
+}
+
+impl Repository {
+    /// Open a repository, creating it if it does not exist yet
+    pub fn init(root: &Path) -> Result<Repository, Error> {
+        for dir in [OBJECTS, SNAPSHOTS].iter() {
+            let dir = root.join(dir);
+            fs::create_dir_all(&dir).map_err(Error::with_path(&dir))?;
+        }
+        Ok(Repository {
+            root: root.to_path_buf(),
+            keys: Keys::default(),
+        })
+    }
+
+    /// Open an existing repository
+    pub fn open(root: &Path) -> Result<Repository, Error> {
+        if !root.exists() {
+            return Err(Error::NotFound(root.to_path_buf()));
+        }
+        if !root.join(OBJECTS).is_dir() || !root.join(SNAPSHOTS).is_dir() {
+            return Err(Error::Repository(format!(
+                "{} is not a confbk repository",
+                root.display()
+            )));
+        }
+        Ok(Repository {
+            root: root.to_path_buf(),
+            keys: Keys::default(),
+        })
+    }
+
+    /// Decrypt the repository key with `keys` so encrypted snapshots can be read
+    pub fn with_keys(mut self, keys: Keys) -> Result<Repository, Error> {
+        let key = self.root.join(KEY);
+        self.keys = if key.exists() && !keys.is_empty() {
+            keys.unlock(&key)?
+        } else {
+            keys
+        };
+        Ok(self)
+    }
+
+    /// Store every path in the repository, only copying contents it doesn't
+    /// hold yet, and return the id of the new snapshot. With `keep_going` the
+    /// snapshot is taken without the files that could not be read and they
+    /// are reported afterwards
+    pub fn backup(
+        &self,
+        paths: &[PathBuf],
+        print: &util::VerbosePrint,
+        options: &BackupOptions,
+    ) -> Result<String, Error> {
+        print.println("Backing up");
+        let _lock = self.lock()?;
+        util::pool(options.jobs)?.install(|| self.store(paths, print, options))
+    }
+
+    // Copy what the repository doesn't hold yet on the worker pool and save
+    // the snapshot
+    fn store(
+        &self,
+        paths: &[PathBuf],
+        print: &util::VerbosePrint,
+        options: &BackupOptions,
+    ) -> Result<String, Error> {
+        let scan = match &options.secrets {
+            Some(scanner) => Some(scanner.scan(paths, options.symlinks, print)?),
+            None => None,
+        };
+        let paths = scan.as_ref().map_or(paths, |scan| &scan.paths[..]);
+        print.debug("Building manifest");
+        let mut failures = Failures::new(options.keep_going);
+        let mut manifest = Manifest::new(paths, options.hash, options.symlinks, &mut failures)?;
+        if let Some(scan) = &scan {
+            scan.apply(&mut manifest)?;
+        }
+        let encryption = self.encryption(options.encrypt.as_ref())?;
+        manifest.encryption = encryption.as_ref().map(Encryption::describe);
+        let mut objects = HashSet::new();
+        let mut copies = Vec::new();
+        // links are kept in the snapshot itself
+        for entry in manifest.entries.iter().filter(|e| e.link.is_none()) {
+            let object = self.object_path(&entry.hash)?;
+            // objects stored before encryption was turned on are stored again
+            // encrypted, so the plaintext doesn't stay behind
+            let stored =
+                object.exists() && (encryption.is_none() || crypto::is_encrypted(&object)?);
+            if stored || !objects.insert(object.clone()) {
+                print.debug(&format!("Already stored \"{}\"", entry.source.display()));
+                continue;
+            }
+            print.debug(&format!(
+                "Storing file \"{}\" as \"{}\"",
+                entry.source.display(),
+                object.display()
+            ));
+            copies.push((&entry.source, object));
+        }
+        let stored: Vec<Result<(), Error>> = copies
+            .par_iter()
+            .map(|(source, object)| {
+                let content = manifest.redacted.get(*source).map(Vec::as_slice);
+                self.store_object(source, object, content, encryption.as_ref())
+            })
+            .collect();
+        let mut added = 0;
+        for ((source, _), result) in copies.iter().zip(stored) {
+            match result {
+                Ok(()) => added += 1,
+                Err(e) => failures.add(source, e)?,
+            }
+        }
+        manifest.entries.retain(|e| !failures.contains(&e.source));
+        let id = self.write_snapshot(&manifest, encryption.as_ref())?;
+        if let Some(key) = &options.sign {
+            key.sign(&self.snapshot_path(&id))?;
+        }
+        print.println(&format!(
+            "Snapshot {}: {} files, {} new objects",
+            id,
+            manifest.entries.len(),
+            added
+        ));
+        failures.finish()?;
+        Ok(id)
+    }
+
+    // Objects and snapshots are encrypted to the repository key once it has
+    // one, which is made by the first backup with --encrypt
+    fn encryption(&self, encrypt: Option<&Encryption>) -> Result<Option<Encryption>, Error> {
+        let public = self.root.join(PUBLIC_KEY);
+        if public.exists() {
+            return crypto::public_key(&public).map(Some);
+        }
+        match encrypt {
+            Some(encryption) => {
+                crypto::new_key(encryption, &self.root.join(KEY), &public).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    // Copy next to the object first so an interrupted run never leaves a partial one
+    fn store_object(
+        &self,
+        source: &Path,
+        object: &Path,
+        content: Option<&[u8]>,
+        encryption: Option<&Encryption>,
+    ) -> Result<(), Error> {
+        let parent = object.parent().unwrap_or(&self.root);
+        fs::create_dir_all(parent).map_err(Error::with_path(parent))?;
+        let partial = object.with_extension("partial");
+        // redacted files are stored from their redacted content
+        let copied = match (content, encryption) {
+            (Some(content), None) => {
+                fs::write(&partial, content).map_err(Error::with_path(&partial))
+            }
+            (None, None) => File::open(source)
+                .map_err(Error::with_path(source))
+                .and_then(|mut file| util::copy_sparse(&mut file, &partial).map(|_| ())),
+            (content, Some(encryption)) => (|| {
+                let mut input: Box<dyn Read> = match content {
+                    Some(content) => Box::new(content),
+                    None => Box::new(File::open(source).map_err(Error::with_path(source))?),
+                };
+                let output = File::create(&partial).map_err(Error::with_path(&partial))?;
+                let mut output = encryption.wrap(output)?;
+                io::copy(&mut input, &mut output).map_err(Error::with_path(source))?;
+                output.finish()?;
+                Ok(())
+            })(),
+        };
+        if let Err(e) = copied {
+            let _ = fs::remove_file(&partial);
+            return Err(e);
+        }
+        fs::rename(&partial, object).map_err(Error::with_path(object))
+    }
+
+    /// Ids of every snapshot, oldest first
+    pub fn snapshots(&self) -> Result<Vec<String>, Error> {
+        let dir = self.root.join(SNAPSHOTS);
+        let mut ids = Vec::new();
+        for entry in fs::read_dir(&dir).map_err(Error::with_path(&dir))? {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("json") {
+                continue;
+            }
+            if let Some(id) = path.file_stem().and_then(|s| s.to_str()) {
+                ids.push(id.to_string());
+            }
+        }
+        ids.sort_by(|a, b| order(a).cmp(&order(b)));
+        Ok(ids)
+    }
+
+    /// Index of a snapshot, `latest` picks the most recent one
+    pub fn snapshot(&self, id: &str) -> Result<Manifest, Error> {
+        let path = self.snapshot_path(&self.resolve(id)?);
+        let file = self.keys.open(&path).map_err(|e| match e {
+            Error::NotFound(_) => {
+                Error::Repository(format!("No snapshot {} in {}", id, self.root.display()))
+            }
+            e => e,
+        })?;
+        Manifest::from_json(file)
+    }
+
+    /// Put every file of a snapshot back where it came from, along with its
+    /// permissions, times and attributes
+    pub fn restore(
+        &self,
+        id: &str,
+        print: &util::VerbosePrint,
+        options: &RestoreOptions,
+    ) -> Result<(), Error> {
+        let id = self.resolve(id)?;
+        let mut manifest = self.snapshot(&id)?;
+        if let Some(key) = &options.signature {
+            key.verify(&self.snapshot_path(&id))?;
+            // the index is signed, the objects it points at are checked
+            // against it before anything is written
+            for entry in manifest.entries.iter().filter(|e| e.link.is_none()) {
+                let object = self.object_path(&entry.hash)?;
+                let hash =
+                    manifest::hash_reader(&mut self.keys.open(&object)?, manifest.hash_algorithm)?;
+                if hash != entry.hash {
+                    return Err(Error::Signature(format!(
+                        "{} in snapshot {} does not match its signed index",
+                        entry.stored.display(),
+                        id
+                    )));
+                }
+            }
+        }
+        restore::leave_out_redacted(&mut manifest, print);
+        if options.dry_run {
+            print.println("Files to be restored:");
+            for entry in &manifest.entries {
+                print.println(&format!(
+                    "    {}",
+                    util::original_path(&entry.stored).display()
+                ));
+            }
+            return Ok(());
+        }
+        print.println("Restoring");
+        let created = attributes::missing_directories(&manifest, util::original_path);
+        for entry in &manifest.entries {
+            let dest = util::original_path(&entry.stored);
+            if let Some(parent) = dest.parent() {
+                if !parent.as_os_str().is_empty() {
+                    fs::create_dir_all(parent).map_err(Error::with_path(parent))?;
+                }
+            }
+            if let Some(target) = &entry.link {
+                print.debug(&format!("Restoring link \"{}\"", dest.display()));
+                util::symlink(target, &dest)?;
+                continue;
+            }
+            if entry.hardlink.is_some() {
+                continue;
+            }
+            let object = self.object_path(&entry.hash)?;
+            print.debug(&format!(
+                "Restoring file \"{}\" to \"{}\"",
+                object.display(),
+                dest.display()
+            ));
+            util::copy_sparse(&mut self.keys.open(&object)?, &dest)?;
+        }
+        restore::restore_hardlinks(&manifest, print, false)?;
+        attributes::apply_manifest(&manifest, util::original_path, &created, options.owner)
+    }
+
+    /// Check that every object a snapshot points at is present and intact,
+    /// and with `signature` that the snapshot index is signed with it
+    pub fn verify(
+        &self,
+        id: &str,
+        print: &util::VerbosePrint,
+        signature: Option<&VerifyingKey>,
+    ) -> Result<(), Error> {
+        let id = self.resolve(id)?;
+        let manifest = self.snapshot(&id)?;
+        print.println("Verifying");
+        if let Some(key) = signature {
+            key.verify(&self.snapshot_path(&id))?;
+            print.println("Signature verified");
+        }
+        let mut failures = 0;
+        for entry in manifest.entries.iter().filter(|e| e.link.is_none()) {
+            let object = self.object_path(&entry.hash)?;
+            let hash = match self.keys.open(&object) {
+                Ok(mut file) => manifest::hash_reader(&mut file, manifest.hash_algorithm)?,
+                Err(Error::NotFound(_)) => {
+                    print.println(&format!("Missing: {}", entry.stored.display()));
+                    failures += 1;
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            if hash == entry.hash {
+                print.debug(&format!("Verified \"{}\"", entry.stored.display()));
+            } else {
+                print.println(&format!("Modified: {}", entry.stored.display()));
+                failures += 1;
+            }
+        }
+        if failures > 0 {
+            return Err(Error::Verification {
+                backup: self.snapshot_path(&id),
+                problems: failures,
+            });
+        }
+        print.println(&format!("Verified {} files", manifest.entries.len()));
+        Ok(())
+    }
+
+    /// Delete snapshots along with every object no remaining snapshot points
+    /// at, returning how many objects were removed
+    pub fn remove(&self, ids: &[String], print: &util::VerbosePrint) -> Result<usize, Error> {
+        let _lock = self.lock()?;
+        for id in ids {
+            let path = self.snapshot_path(&self.resolve(id)?);
+            print.debug(&format!("Removing snapshot \"{}\"", path.display()));
+            fs::remove_file(&path).map_err(Error::with_path(&path))?;
+            let signature = signature::signature_path(&path);
+            if signature.exists() {
+                fs::remove_file(&signature).map_err(Error::with_path(&signature))?;
+            }
+        }
+        let mut used = HashSet::new();
+        for id in self.snapshots()? {
+            for entry in self.snapshot(&id)?.entries {
+                if entry.link.is_some() {
+                    continue;
+                }
+                used.insert(self.object_path(&entry.hash)?);
+            }
+        }
+        let mut removed = 0;
+        let objects = self.root.join(OBJECTS);
+        for dir in fs::read_dir(&objects).map_err(Error::with_path(&objects))? {
+            let dir = dir?.path();
+            if !dir.is_dir() {
+                continue;
+            }
+            for object in fs::read_dir(&dir).map_err(Error::with_path(&dir))? {
+                let object = object?.path();
+                if !used.contains(&object) {
+                    print.debug(&format!("Removing object \"{}\"", object.display()));
+                    fs::remove_file(&object).map_err(Error::with_path(&object))?;
+                    removed += 1;
+                }
+            }
+        }
+        Ok(removed)
+    }
+
+    // Only one confbk may change a repository at a time, otherwise an object
+    // could be removed as unused just before a backup points at it
+    fn lock(&self) -> Result<Lock, Error> {
+        let path = self.root.join(LOCK);
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(_) => Ok(Lock(path)),
+            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                Err(Error::Repository(format!(
+                    "{} is in use by another confbk, remove {} if it is not",
+                    self.root.display(),
+                    path.display()
+                )))
+            }
+            Err(e) => Err(Error::with_path(&path)(e)),
+        }
+    }
+
+    // Turn `latest` into the id of the newest snapshot
+    fn resolve(&self, id: &str) -> Result<String, Error> {
+        if id.is_empty() || id.contains('/') {
+            return Err(Error::Repository(format!("Invalid snapshot id {}", id)));
+        }
+        if id != LATEST {
+            return Ok(id.to_string());
+        }
+        self.snapshots()?
+            .pop()
+            .ok_or_else(|| Error::Repository(format!("{} has no snapshots", self.root.display())))
+    }
+
+    // Save a snapshot index under a new id made from the current time
+    fn write_snapshot(
+        &self,
+        manifest: &Manifest,
+        encryption: Option<&Encryption>,
+    ) -> Result<String, Error> {
+        let json = manifest.to_json()?;
+        let base = Utc::now().format("%Y%m%dT%H%M%SZ").to_string();
+        let mut id = base.clone();
+        for n in 1.. {
+            let path = self.snapshot_path(&id);
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(file) => {
+                    match encryption {
+                        Some(encryption) => {
+                            let mut output = encryption.wrap(file)?;
+                            output.write_all(&json).map_err(Error::with_path(&path))?;
+                            output.finish()?;
+                        }
+                        None => (&file).write_all(&json).map_err(Error::with_path(&path))?,
+                    }
+                    break;
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                    id = format!("{}-{}", base, n);
+                }
+                Err(e) => return Err(Error::with_path(&path)(e)),
+            }
+        }
+        Ok(id)
+    }
+
+    fn snapshot_path(&self, id: &str) -> PathBuf {
+        self.root.join(SNAPSHOTS).join(format!("{}.json", id))
+    }
+
+    // Objects are spread over directories named after the first two characters
+    // of their hash to keep directories small
+    fn object_path(&self, hash: &str) -> Result<PathBuf, Error> {
+        if hash.len() < 3 || !hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(Error::Repository(format!("Invalid object hash {}", hash)));
+        }
+        Ok(self.root.join(OBJECTS).join(&hash[..2]).join(&hash[2..]))
+    }
+}
+
+// Held while a repository is being changed, released when dropped
+struct Lock(PathBuf);
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+// Snapshots taken within the same second get a numeric suffix, sort them by it
+fn order(id: &str) -> (&str, u32) {
+    match id.split_once('-') {
+        Some((time, n)) => (time, n.parse().unwrap_or(0)),
+        None => (id, 0),
+    }
+}
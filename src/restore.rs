@@ -0,0 +1,399 @@
+use super::archive;
+use super::archive::{Opened, ZipSource};
+use super::attributes;
+use super::crypto::Keys;
+use super::error::Error;
+use super::manifest::{self, Entry, Manifest};
+use super::signature::VerifyingKey;
+use super::util::{self, Symlinks};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::io::Read;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use zip::ZipArchive;
+
+/// How a backup should be restored
+pub struct RestoreOptions {
+    pub dry_run: bool,
+    /// Give files back to the user and group that owned them
+    pub owner: bool,
+    /// What encrypted backups are decrypted with
+    pub keys: Keys,
+    /// Key the backup, and any it is incremental against, has to be signed with
+    pub signature: Option<VerifyingKey>,
+}
+
+/// Put every file of a backup directory or archive back where it came from,
+/// along with the permissions, times and attributes in its manifest
+pub fn restore(
+    backup: &Path,
+    print: &util::VerbosePrint,
+    options: &RestoreOptions,
+) -> Result<(), Error> {
+    let dry_run = options.dry_run;
+    if !backup.exists() {
+        return Err(Error::NotFound(backup.to_path_buf()));
+    }
+    if let Some(key) = &options.signature {
+        verify_signed(key, backup, &options.keys)?;
+        print.debug(&format!("Signature of \"{}\" verified", backup.display()));
+    }
+    let mut manifest = archive::read_manifest(backup, &options.keys)?;
+    let redacted = match &mut manifest {
+        Some(manifest) => leave_out_redacted(manifest, print),
+        None => HashSet::new(),
+    };
+    let created = match &manifest {
+        Some(manifest) => attributes::missing_directories(manifest, util::original_path),
+        None => HashSet::new(),
+    };
+    // hard links are made once the files they point to are back
+    let skipped: HashSet<&Path> = match &manifest {
+        Some(manifest) => manifest
+            .entries
+            .iter()
+            .filter(|e| e.hardlink.is_some())
+            .map(|e| e.stored.as_path())
+            .chain(redacted.iter().map(|p| p.as_path()))
+            .collect(),
+        None => HashSet::new(),
+    };
+    if backup.is_dir() {
+        restore_dir(backup, print, dry_run, &skipped)?;
+    } else {
+        restore_archive(backup, print, dry_run, &skipped, &options.keys)?;
+    }
+    let manifest = match &manifest {
+        Some(manifest) => manifest,
+        None => return Ok(()),
+    };
+    restore_unchanged(manifest, print, options)?;
+    restore_hardlinks(manifest, print, dry_run)?;
+    if !dry_run {
+        print.debug("Restoring permissions, times and attributes");
+        attributes::apply_manifest(manifest, util::original_path, &created, options.owner)?;
+    }
+    Ok(())
+}
+
+// Files of the backup itself, without the manifest and the files in `skipped`
+fn restore_dir(
+    root: &Path,
+    print: &util::VerbosePrint,
+    dry_run: bool,
+    skipped: &HashSet<&Path>,
+) -> Result<(), Error> {
+    let files: Vec<_> = util::dir_files(root, false, Symlinks::Preserve, false)?
+        .into_iter()
+        .filter(|f| match f.strip_prefix(root) {
+            Ok(stored) => stored != Path::new(manifest::MANIFEST_NAME) && !skipped.contains(stored),
+            Err(_) => true,
+        })
+        .collect();
+    if dry_run {
+        print.println("Files to be restored:");
+        for file in &files {
+            let stored = file.strip_prefix(root).unwrap_or(file);
+            print.println(&format!("    {}", util::original_path(stored).display()));
+        }
+        return Ok(());
+    }
+    print.println("Restoring");
+    for file in &files {
+        let stored = file.strip_prefix(root).unwrap_or(file);
+        let dest = util::original_path(stored);
+        print.debug(&format!(
+            "Restoring file \"{}\" to \"{}\"",
+            file.display(),
+            dest.display()
+        ));
+        create_parent(&dest)?;
+        if util::is_symlink(file) {
+            let target = fs::read_link(file).map_err(Error::with_path(file))?;
+            util::symlink(&target, &dest)?;
+        } else {
+            util::copy_sparse(
+                &mut File::open(file).map_err(Error::with_path(file))?,
+                &dest,
+            )?;
+            let mode = fs::metadata(file)
+                .map_err(Error::with_path(file))?
+                .permissions()
+                .mode();
+            fs::set_permissions(&dest, fs::Permissions::from_mode(mode))
+                .map_err(Error::with_path(&dest))?;
+        }
+    }
+    Ok(())
+}
+
+fn restore_archive(
+    backup: &Path,
+    print: &util::VerbosePrint,
+    dry_run: bool,
+    skipped: &HashSet<&Path>,
+    keys: &Keys,
+) -> Result<(), Error> {
+    let mut archive = match archive::open(backup, keys)? {
+        Opened::Zip(zip) => return restore_zip(zip, print, dry_run, skipped),
+        Opened::Tar(archive) => archive,
+    };
+    if dry_run {
+        print.println("Files to be restored:");
+    } else {
+        print.println("Restoring");
+    }
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if entry.header().entry_type().is_dir() {
+            continue;
+        }
+        let stored = archive::stored_path(&entry.path()?)?;
+        if stored == Path::new(manifest::MANIFEST_NAME) || skipped.contains(stored.as_path()) {
+            continue;
+        }
+        let dest = util::original_path(&stored);
+        if dry_run {
+            print.println(&format!("    {}", dest.display()));
+            continue;
+        }
+        print.debug(&format!(
+            "Restoring file \"{}\" to \"{}\"",
+            stored.display(),
+            dest.display()
+        ));
+        archive::unpack(&mut entry, &dest)?;
+    }
+    Ok(())
+}
+
+fn restore_zip(
+    mut zip: ZipArchive<ZipSource>,
+    print: &util::VerbosePrint,
+    dry_run: bool,
+    skipped: &HashSet<&Path>,
+) -> Result<(), Error> {
+    if dry_run {
+        print.println("Files to be restored:");
+    } else {
+        print.println("Restoring");
+    }
+    for index in 0..zip.len() {
+        let mut entry = zip.by_index(index)?;
+        if entry.is_dir() {
+            continue;
+        }
+        let stored = archive::stored_path(&archive::zip_entry_path(&entry)?)?;
+        if stored == Path::new(manifest::MANIFEST_NAME) || skipped.contains(stored.as_path()) {
+            continue;
+        }
+        let dest = util::original_path(&stored);
+        if dry_run {
+            print.println(&format!("    {}", dest.display()));
+            continue;
+        }
+        print.debug(&format!(
+            "Restoring file \"{}\" to \"{}\"",
+            stored.display(),
+            dest.display()
+        ));
+        create_parent(&dest)?;
+        if entry.is_symlink() {
+            let mut target = Vec::new();
+            entry.read_to_end(&mut target)?;
+            util::symlink(Path::new(OsStr::from_bytes(&target)), &dest)?;
+            continue;
+        }
+        util::copy_sparse(&mut entry, &dest)?;
+        if let Some(mode) = entry.unix_mode() {
+            fs::set_permissions(&dest, fs::Permissions::from_mode(mode))?;
+        }
+    }
+    Ok(())
+}
+
+// Files of an incremental backup that are held by the earlier backups it points to
+fn restore_unchanged(
+    manifest: &Manifest,
+    print: &util::VerbosePrint,
+    options: &RestoreOptions,
+) -> Result<(), Error> {
+    let dry_run = options.dry_run;
+    let mut bases: BTreeMap<&Path, BTreeMap<&Path, &Entry>> = BTreeMap::new();
+    for entry in &manifest.entries {
+        let base = match &entry.base {
+            Some(base) if entry.hardlink.is_none() => base,
+            _ => continue,
+        };
+        // the manifest already says where links point
+        if let Some(target) = &entry.link {
+            let dest = util::original_path(&entry.stored);
+            if dry_run {
+                print.println(&format!("    {}", dest.display()));
+            } else {
+                print.debug(&format!("Restoring link \"{}\"", dest.display()));
+                create_parent(&dest)?;
+                util::symlink(target, &dest)?;
+            }
+            continue;
+        }
+        bases.entry(base).or_default().insert(&entry.stored, entry);
+    }
+    for (base, mut entries) in bases {
+        if !base.exists() {
+            return Err(Error::NotFound(base.to_path_buf()));
+        }
+        if let Some(key) = &options.signature {
+            verify_signed(key, base, &options.keys)?;
+        }
+        if dry_run {
+            for stored in entries.keys() {
+                print.println(&format!("    {}", util::original_path(stored).display()));
+            }
+            continue;
+        }
+        archive::walk(base, &options.keys, |stored, reader| {
+            if let Some(entry) = entries.remove(stored) {
+                let dest = util::original_path(stored);
+                print.debug(&format!(
+                    "Restoring file \"{}\" from \"{}\"",
+                    dest.display(),
+                    base.display()
+                ));
+                create_parent(&dest)?;
+                util::copy_sparse(reader, &dest)?;
+                fs::set_permissions(&dest, fs::Permissions::from_mode(entry.mode))
+                    .map_err(Error::with_path(&dest))?;
+            }
+            Ok(())
+        })?;
+        if let Some(stored) = entries.keys().next() {
+            return Err(Error::Archive(format!(
+                "{} is missing from {}",
+                stored.display(),
+                base.display()
+            )));
+        }
+    }
+    Ok(())
+}
+
+// Take the files whose secrets were redacted out of the manifest, along with
+// hard links to them, so the placeholders never replace the real files. Returns
+// where they are stored
+pub(crate) fn leave_out_redacted(
+    manifest: &mut Manifest,
+    print: &util::VerbosePrint,
+) -> HashSet<PathBuf> {
+    let mut redacted = HashSet::new();
+    for entry in &manifest.entries {
+        let first = entry.hardlink.as_ref().unwrap_or(&entry.stored);
+        if !entry.redacted.is_empty() || redacted.contains(first) {
+            redacted.insert(entry.stored.clone());
+        }
+    }
+    if redacted.is_empty() {
+        return redacted;
+    }
+    print.println("Left alone, their secrets were redacted in the backup:");
+    for entry in &manifest.entries {
+        if redacted.contains(&entry.stored) {
+            print.println(&format!(
+                "    {}",
+                util::original_path(&entry.stored).display()
+            ));
+        }
+    }
+    manifest.entries.retain(|e| !redacted.contains(&e.stored));
+    redacted
+}
+
+// Check the signature of a backup before anything is restored from it. A
+// directory is signed through its manifest, so every file in it has to have
+// the hash the manifest records as well
+fn verify_signed(key: &VerifyingKey, backup: &Path, keys: &Keys) -> Result<(), Error> {
+    key.verify(backup)?;
+    if !backup.is_dir() {
+        return Ok(());
+    }
+    let manifest = archive::read_manifest(backup, keys)?
+        .ok_or_else(|| Error::Manifest(format!("{} has no manifest", backup.display())))?;
+    let mut hashes: HashMap<&Path, &str> = manifest
+        .entries
+        .iter()
+        .filter(|e| e.base.is_none())
+        .map(|e| (e.stored.as_path(), e.hash.as_str()))
+        .collect();
+    archive::walk(backup, keys, |stored, reader| {
+        if stored == Path::new(manifest::MANIFEST_NAME) {
+            return Ok(());
+        }
+        let signed = hashes.remove(stored).ok_or_else(|| {
+            Error::Signature(format!(
+                "{} in {} is not in its signed manifest",
+                stored.display(),
+                backup.display()
+            ))
+        })?;
+        if manifest::hash_reader(reader, manifest.hash_algorithm)? != signed {
+            return Err(Error::Signature(format!(
+                "{} in {} does not match its signed manifest",
+                stored.display(),
+                backup.display()
+            )));
+        }
+        Ok(())
+    })?;
+    // hard links may be left out, the file they point to holds the contents
+    match manifest
+        .entries
+        .iter()
+        .find(|e| e.hardlink.is_none() && hashes.contains_key(e.stored.as_path()))
+    {
+        Some(entry) => Err(Error::Signature(format!(
+            "{} is missing from {}",
+            entry.stored.display(),
+            backup.display()
+        ))),
+        None => Ok(()),
+    }
+}
+
+// Link every file that was a hard link to an earlier one back to it
+pub(crate) fn restore_hardlinks(
+    manifest: &Manifest,
+    print: &util::VerbosePrint,
+    dry_run: bool,
+) -> Result<(), Error> {
+    for entry in &manifest.entries {
+        let first = match &entry.hardlink {
+            Some(first) => util::original_path(first),
+            None => continue,
+        };
+        let dest = util::original_path(&entry.stored);
+        if dry_run {
+            print.println(&format!("    {}", dest.display()));
+            continue;
+        }
+        print.debug(&format!(
+            "Linking file \"{}\" to \"{}\"",
+            dest.display(),
+            first.display()
+        ));
+        create_parent(&dest)?;
+        util::hard_link(&first, &dest)?;
+    }
+    Ok(())
+}
+
+fn create_parent(dest: &Path) -> Result<(), Error> {
+    if let Some(parent) = dest.parent() {
+        if !parent.as_os_str().is_empty() {
+            fs::create_dir_all(parent).map_err(Error::with_path(parent))?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,346 @@
+use super::error::Error;
+use super::manifest::{self, Manifest};
+use super::util::{self, Symlinks, VerbosePrint};
+use rayon::prelude::*;
+use regex::bytes::{Regex, RegexBuilder};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// What to do with a file that looks like it holds a secret
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SecretPolicy {
+    /// Back it up as it is and list it in the report
+    Warn,
+    /// Leave it out of the backup
+    Skip,
+    /// Back up a copy with every secret replaced by a placeholder
+    Redact,
+}
+
+impl FromStr for SecretPolicy {
+    type Err = String;
+    fn from_str(s: &str) -> Result<SecretPolicy, String> {
+        match s {
+            "warn" => Ok(SecretPolicy::Warn),
+            "skip" => Ok(SecretPolicy::Skip),
+            "redact" => Ok(SecretPolicy::Redact),
+            _ => Err(format!(
+                "Unknown secret policy {}, expected one of warn, skip or redact",
+                s
+            )),
+        }
+    }
+}
+
+// Secrets looked for in every file. A `secret` group is the part that gets
+// redacted, otherwise the whole match is
+const RULES: &[(&str, &str)] = &[
+    (
+        "private-key",
+        r"-----BEGIN [A-Z ]*PRIVATE KEY( BLOCK)?-----(?s:.*?)(-----END [A-Z ]*PRIVATE KEY( BLOCK)?-----|\z)",
+    ),
+    ("aws-access-key", r"\b(AKIA|ASIA)[0-9A-Z]{16}\b"),
+    (
+        "aws-secret-key",
+        r"(?i)aws_?secret_?access_?key\s*[=:]\s*(?P<secret>[A-Za-z0-9/+=]{40})",
+    ),
+    (
+        "github-token",
+        r"\b(gh[pousr]_[A-Za-z0-9]{36,}|github_pat_[A-Za-z0-9_]{40,})\b",
+    ),
+    ("slack-token", r"\bxox[abprs]-[A-Za-z0-9-]{10,}"),
+    (
+        "password",
+        r#"(?im)^[ \t]*[\w.-]*(password|passwd|secret|token|api_?key)[\w.-]*[ \t]*[=:][ \t]*(?P<secret>[^\s#;'"][^\r\n]*?|'[^'\r\n]*'|"[^"\r\n]*")[ \t]*$"#,
+    ),
+];
+
+// Files with a NUL byte among their first bytes are binary and not scanned
+const BINARY_CHECK: usize = 8000;
+
+// Larger files are not read into memory to be scanned, they are listed in
+// the report instead
+const SCAN_LIMIT: u64 = 4 * 1024 * 1024;
+
+// What scanning a file turned up. A file with a secret in it comes with its
+// contents and the rule, line and byte offset of each one
+enum Scanned {
+    Nothing,
+    TooLarge,
+    Found(Vec<u8>, Vec<(usize, usize, usize)>),
+}
+
+// A kind of secret and how to find it
+struct Rule {
+    name: String,
+    regex: Regex,
+}
+
+/// Finds likely secrets in files before they are backed up
+pub struct Scanner {
+    policy: SecretPolicy,
+    rules: Vec<Rule>,
+}
+
+/// A likely secret, where it is and which rule found it. The secret itself
+/// is never kept
+#[derive(Debug)]
+pub struct Finding {
+    pub path: PathBuf,
+    pub line: usize,
+    pub rule: String,
+}
+
+// Paths left to back up once a scan is done, and the redacted contents of
+// files by path
+pub(crate) struct Scan {
+    pub(crate) paths: Vec<PathBuf>,
+    pub(crate) redacted: HashMap<PathBuf, Redacted>,
+}
+
+pub(crate) struct Redacted {
+    content: Vec<u8>,
+    rules: Vec<String>,
+}
+
+impl Scanner {
+    /// Look for the built in secrets and for every regex in `patterns`
+    pub fn new(policy: SecretPolicy, patterns: &[String]) -> Result<Scanner, Error> {
+        let mut rules: Vec<Rule> = RULES
+            .iter()
+            .map(|(name, regex)| Rule {
+                name: name.to_string(),
+                regex: Regex::new(regex).expect("built in secret rules are valid"),
+            })
+            .collect();
+        for pattern in patterns {
+            let regex = RegexBuilder::new(pattern)
+                .multi_line(true)
+                .build()
+                .map_err(|e| {
+                    Error::Pattern(format!("Invalid secret pattern {}: {}", pattern, e))
+                })?;
+            rules.push(Rule {
+                name: pattern.to_string(),
+                regex,
+            });
+        }
+        Ok(Scanner { policy, rules })
+    }
+
+    // Scan every file about to be backed up and report what was found.
+    // Directories holding a file that is skipped or redacted are replaced by
+    // their files so each one can be handled on its own
+    pub(crate) fn scan(
+        &self,
+        paths: &[PathBuf],
+        symlinks: Symlinks,
+        print: &VerbosePrint,
+    ) -> Result<Scan, Error> {
+        print.debug("Scanning for secrets");
+        let mut groups = Vec::new();
+        for path in paths {
+            if symlinks != Symlinks::Follow && util::is_symlink(path) {
+                groups.push((path, Vec::new()));
+            } else if path.is_dir() {
+                // unreadable directories are left for the backup to report
+                let files = util::dir_files(path, false, symlinks, false).unwrap_or_default();
+                groups.push((path, files));
+            } else {
+                groups.push((path, vec![path.to_path_buf()]));
+            }
+        }
+        let files: Vec<&PathBuf> = groups.iter().flat_map(|(_, files)| files).collect();
+        // unreadable files are left for the backup to report
+        let scanned: Vec<Scanned> = files.par_iter().map(|file| self.find(file)).collect();
+        let mut found = HashMap::new();
+        let mut unscanned = Vec::new();
+        for (file, scanned) in files.into_iter().zip(scanned) {
+            match scanned {
+                Scanned::Nothing => (),
+                Scanned::TooLarge => unscanned.push(file.as_path()),
+                Scanned::Found(content, matches) => {
+                    found.insert(file.as_path(), (content, matches));
+                }
+            }
+        }
+
+        let mut scan = Scan {
+            paths: Vec::new(),
+            redacted: HashMap::new(),
+        };
+        let mut findings = Vec::new();
+        for (path, files) in &groups {
+            let flagged = files.iter().any(|f| found.contains_key(f.as_path()));
+            if !flagged || self.policy == SecretPolicy::Warn {
+                scan.paths.push(path.to_path_buf());
+            }
+            for file in files {
+                let (content, matches) = match found.get(file.as_path()) {
+                    Some(found) => found,
+                    None if flagged && self.policy != SecretPolicy::Warn => {
+                        scan.paths.push(file.to_path_buf());
+                        continue;
+                    }
+                    None => continue,
+                };
+                for &(rule, line, _) in matches {
+                    findings.push(Finding {
+                        path: file.to_path_buf(),
+                        line,
+                        rule: self.rules[rule].name.clone(),
+                    });
+                }
+                if self.policy == SecretPolicy::Redact {
+                    let redacted = self.redact(content, matches);
+                    scan.redacted.insert(file.to_path_buf(), redacted);
+                    scan.paths.push(file.to_path_buf());
+                }
+            }
+        }
+        self.report(&findings, &unscanned, print);
+        Ok(scan)
+    }
+
+    // Every secret in a file, nothing for binary or unreadable files. Only
+    // the start of a file is read to tell whether it is binary, and text files
+    // over SCAN_LIMIT are not read at all
+    fn find(&self, file: &Path) -> Scanned {
+        let mut file = match File::open(file) {
+            Ok(file) => file,
+            Err(_) => return Scanned::Nothing,
+        };
+        let mut content = Vec::new();
+        if (&mut file)
+            .take(BINARY_CHECK as u64)
+            .read_to_end(&mut content)
+            .is_err()
+            || content.contains(&0)
+        {
+            return Scanned::Nothing;
+        }
+        if file.metadata().is_ok_and(|m| m.len() > SCAN_LIMIT) {
+            return Scanned::TooLarge;
+        }
+        // files can grow while they are read, so the limit holds either way
+        let left = SCAN_LIMIT + 1 - content.len() as u64;
+        if (&mut file).take(left).read_to_end(&mut content).is_err() {
+            return Scanned::Nothing;
+        }
+        if content.len() as u64 > SCAN_LIMIT {
+            return Scanned::TooLarge;
+        }
+        let mut found = Vec::new();
+        for (index, rule) in self.rules.iter().enumerate() {
+            for captures in rule.regex.captures_iter(&content) {
+                let secret = captures
+                    .name("secret")
+                    .unwrap_or_else(|| captures.get(0).unwrap());
+                if secret.as_bytes().is_empty() {
+                    continue;
+                }
+                let line = content[..secret.start()]
+                    .iter()
+                    .filter(|&&b| b == b'\n')
+                    .count()
+                    + 1;
+                found.push((index, line, secret.start()));
+            }
+        }
+        if found.is_empty() {
+            return Scanned::Nothing;
+        }
+        found.sort_by_key(|&(_, line, start)| (line, start));
+        Scanned::Found(content, found)
+    }
+
+    // A copy of the contents of a file with every secret replaced by a
+    // placeholder naming its rule, from the same read that found them
+    fn redact(&self, content: &[u8], matches: &[(usize, usize, usize)]) -> Redacted {
+        let mut content = content.to_vec();
+        let mut found: Vec<usize> = matches.iter().map(|&(rule, _, _)| rule).collect();
+        found.sort_unstable();
+        found.dedup();
+        let mut rules = Vec::new();
+        for rule in found {
+            let rule = &self.rules[rule];
+            let placeholder = format!("<redacted:{}>", rule.name);
+            content = rule
+                .regex
+                .replace_all(&content, |captures: &regex::bytes::Captures| {
+                    let whole = captures.get(0).unwrap();
+                    let secret = captures.name("secret").unwrap_or(whole);
+                    // empty matches aren't reported by find, so nothing is replaced
+                    if secret.as_bytes().is_empty() {
+                        return whole.as_bytes().to_vec();
+                    }
+                    let mut replaced = whole.as_bytes()[..secret.start() - whole.start()].to_vec();
+                    replaced.extend_from_slice(placeholder.as_bytes());
+                    replaced.extend_from_slice(&whole.as_bytes()[secret.end() - whole.start()..]);
+                    replaced
+                })
+                .into_owned();
+            rules.push(rule.name.clone());
+        }
+        Redacted { content, rules }
+    }
+
+    // List every finding along with what was done about it, and the files
+    // too large to be scanned
+    fn report(&self, findings: &[Finding], unscanned: &[&Path], print: &VerbosePrint) {
+        if findings.is_empty() {
+            print.debug("No secrets found");
+        } else {
+            let action = match self.policy {
+                SecretPolicy::Warn => "backed up as is",
+                SecretPolicy::Skip => "left out",
+                SecretPolicy::Redact => "redacted",
+            };
+            print.println(&format!("Possible secrets found, {}:", action));
+            for finding in findings {
+                print.println(&format!(
+                    "    {}:{}: {}",
+                    finding.path.display(),
+                    finding.line,
+                    finding.rule
+                ));
+            }
+        }
+        if unscanned.is_empty() {
+            return;
+        }
+        print.println(&format!(
+            "Not scanned for secrets, larger than {}, backed up as is:",
+            util::human_size(SCAN_LIMIT)
+        ));
+        for path in unscanned {
+            print.println(&format!("    {}", path.display()));
+        }
+    }
+}
+
+impl Scan {
+    // Record redacted files in the manifest, their size and hash become those
+    // of the redacted copy that is backed up
+    pub(crate) fn apply(&self, manifest: &mut Manifest) -> Result<(), Error> {
+        for entry in &mut manifest.entries {
+            if let Some(redacted) = self.redacted.get(&entry.source) {
+                entry.size = redacted.content.len() as u64;
+                entry.hash =
+                    manifest::hash_reader(&mut &redacted.content[..], manifest.hash_algorithm)?;
+                entry.redacted = redacted.rules.clone();
+            }
+        }
+        manifest.redacted = self
+            .redacted
+            .iter()
+            .map(|(path, redacted)| (path.clone(), redacted.content.clone()))
+            .collect();
+        Ok(())
+    }
+}
@@ -0,0 +1,121 @@
+use super::error::Error;
+use super::manifest;
+use chrono::Utc;
+use minisign::{PublicKey, PublicKeyBox, SecretKey, SignatureBox};
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, IsTerminal};
+use std::path::{Path, PathBuf};
+
+/// Environment variable holding the password of an encrypted minisign secret key
+pub const PASSWORD_VAR: &str = "CONFBK_SIGN_PASSWORD";
+
+/// Appended to the name of a backup to get its detached signature
+pub const EXTENSION: &str = ".minisig";
+
+/// A minisign secret key backups are signed with
+pub struct SigningKey {
+    key: SecretKey,
+}
+
+impl SigningKey {
+    /// Load a minisign secret key. Encrypted keys are decrypted with the
+    /// password in CONFBK_SIGN_PASSWORD, or one typed in on a terminal
+    pub fn load(path: &Path) -> Result<SigningKey, Error> {
+        let content = fs::read_to_string(path).map_err(Error::with_path(path))?;
+        let password = match env::var(PASSWORD_VAR) {
+            Ok(password) => Some(password),
+            Err(_) if io::stdin().is_terminal() => None,
+            Err(_) => Some(String::new()),
+        };
+        let key = SecretKey::from_box(content.into(), password).map_err(|e| {
+            Error::Signature(format!(
+                "Cannot read secret key {}: {}, set {} if it is encrypted",
+                path.display(),
+                e,
+                PASSWORD_VAR
+            ))
+        })?;
+        Ok(SigningKey { key })
+    }
+
+    // Write a detached signature of the backup at `backup` next to it
+    pub(crate) fn sign(&self, backup: &Path) -> Result<PathBuf, Error> {
+        let signed = signed_file(backup);
+        let name = signed
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let trusted = format!("timestamp:{}\tfile:{}", Utc::now().timestamp(), name);
+        let file = File::open(&signed).map_err(Error::with_path(&signed))?;
+        let signature = minisign::sign(None, &self.key, file, Some(&trusted), None)
+            .map_err(|e| Error::Signature(format!("Cannot sign {}: {}", signed.display(), e)))?;
+        let path = signature_path(backup);
+        fs::write(&path, signature.into_string()).map_err(Error::with_path(&path))?;
+        Ok(path)
+    }
+}
+
+/// A minisign public key backups have to be signed with
+pub struct VerifyingKey {
+    key: PublicKey,
+}
+
+impl VerifyingKey {
+    /// A minisign public key file, or the base64 key on its second line
+    pub fn load(value: &str) -> Result<VerifyingKey, Error> {
+        let path = Path::new(value);
+        let key = if path.is_file() {
+            let content = fs::read_to_string(path).map_err(Error::with_path(path))?;
+            PublicKeyBox::from_string(&content).and_then(PublicKey::from_box)
+        } else {
+            PublicKey::from_base64(value)
+        };
+        let key = key.map_err(|e| {
+            Error::Signature(format!("Invalid minisign public key {}: {}", value, e))
+        })?;
+        Ok(VerifyingKey { key })
+    }
+
+    // Check the detached signature of the backup at `backup`, refusing
+    // backups that have none
+    pub(crate) fn verify(&self, backup: &Path) -> Result<(), Error> {
+        let path = signature_path(backup);
+        if !path.is_file() {
+            return Err(Error::Signature(format!(
+                "{} is not signed, {} is missing",
+                backup.display(),
+                path.display()
+            )));
+        }
+        let signature = SignatureBox::from_file(&path).map_err(|e| {
+            Error::Signature(format!("Invalid signature {}: {}", path.display(), e))
+        })?;
+        let signed = signed_file(backup);
+        let file = File::open(&signed).map_err(Error::with_path(&signed))?;
+        minisign::verify(&self.key, &signature, file, true, false, false).map_err(|e| {
+            Error::Signature(format!(
+                "Signature of {} does not match: {}",
+                backup.display(),
+                e
+            ))
+        })
+    }
+}
+
+// Detached signature of a backup directory, archive or snapshot index
+pub(crate) fn signature_path(backup: &Path) -> PathBuf {
+    let mut name = backup.as_os_str().to_os_string();
+    name.push(EXTENSION);
+    PathBuf::from(name)
+}
+
+// Archives and snapshot indexes are signed whole, directories through their
+// manifest, which holds the hash of every file
+fn signed_file(backup: &Path) -> PathBuf {
+    if backup.is_dir() {
+        backup.join(manifest::MANIFEST_NAME)
+    } else {
+        backup.to_path_buf()
+    }
+}
@@ -1,31 +1,41 @@
+use super::archive;
+use super::attributes;
+use super::crypto::{Encryption, Keys};
+use super::error::Error;
+use super::manifest::{self, Manifest};
+use super::repo::Repository;
+use super::secrets::Scanner;
+use super::signature::{self, SigningKey};
 use super::util;
-use duct::cmd;
-use fs_extra::dir::{self, DirContent};
-use std::ffi::OsStr;
-use std::ffi::OsString;
-use std::fs;
-use std::io;
-use std::path::PathBuf;
-use std::process;
+use chrono::format::{Item, StrftimeItems};
+use chrono::Local;
+use ignore::WalkBuilder;
+use rayon::prelude::*;
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::ffi::{OsStr, OsString};
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs as unix_fs;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+
+/// Ignore file read from directories being backed up, on top of .gitignore and .ignore
+pub const IGNORE_FILE: &str = ".confbkignore";
+
+/// Directory inside a backup that holds files given by absolute path
+pub const ABSOLUTE_ROOT: &str = "_root";
+
+/// Name given to backups when no --out is given
+pub const DEFAULT_OUT: &str = "confbk-{date}";
+
 pub enum VerboseLevel {
     On,
     Reg,
     Off,
 }
 
-pub struct FatalError();
-
-impl FatalError {
-    pub fn file_not_found(file_name: &str) {
-        eprintln!("Error: File {} not found", file_name);
-        process::exit(1);
-    }
-    pub fn error(msg: &str) {
-        eprintln!("{}", msg);
-        process::exit(1);
-    }
-}
-
 pub struct VerbosePrint {
     pub level: VerboseLevel,
 }
@@ -44,70 +54,877 @@ impl VerbosePrint {
     }
 }
 
-// Backup function that will backup files
+/// How a backup should be written
+pub struct BackupOptions {
+    pub dry_run: bool,
+    pub format: archive::Format,
+    pub compression: archive::Compression,
+    pub level: Option<u32>,
+    pub hash: manifest::HashAlgorithm,
+    /// Earlier backup to compare against, only changed files are stored
+    pub incremental: Option<PathBuf>,
+    /// Repository to add a snapshot to instead of writing `out`
+    pub repo: Option<PathBuf>,
+    /// What to do when the backup already exists
+    pub on_existing: OnExisting,
+    /// How symbolic links among the paths are backed up
+    pub symlinks: Symlinks,
+    /// Back up everything that can be read and report the rest at the end
+    pub keep_going: bool,
+    /// How many files are copied, hashed and compressed at once
+    pub jobs: usize,
+    /// What archives and repository objects are encrypted to
+    pub encrypt: Option<Encryption>,
+    /// Keys to read an encrypted earlier backup with
+    pub keys: Keys,
+    /// Key to write a detached signature of the backup with
+    pub sign: Option<SigningKey>,
+    /// Scan for secrets before backing up, and what to do with files holding them
+    pub secrets: Option<Scanner>,
+}
+
+/// What to do when a backup of the same name is already there
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OnExisting {
+    /// Stop with an error
+    Fail,
+    /// Replace it once the new backup has been written
+    Overwrite,
+    /// Copy the new files into the existing directory
+    Merge,
+    /// Move it aside to `<name>-old`
+    Rename,
+    /// Only copy files that changed into the existing directory
+    Update,
+}
+
+impl FromStr for OnExisting {
+    type Err = String;
+    fn from_str(s: &str) -> Result<OnExisting, String> {
+        match s {
+            "fail" => Ok(OnExisting::Fail),
+            "overwrite" => Ok(OnExisting::Overwrite),
+            "merge" => Ok(OnExisting::Merge),
+            "rename" => Ok(OnExisting::Rename),
+            "update" => Ok(OnExisting::Update),
+            _ => Err(format!(
+                "Unknown policy {}, expected one of fail, overwrite, merge, rename or update",
+                s
+            )),
+        }
+    }
+}
+
+/// What to do with symbolic links found among the files to back up
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Symlinks {
+    /// Store the link itself and recreate it on restore
+    Preserve,
+    /// Store what the link points to, skipping links that loop or dangle
+    #[default]
+    Follow,
+    /// Leave links out of the backup
+    Skip,
+}
+
+impl FromStr for Symlinks {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Symlinks, String> {
+        match s {
+            "preserve" => Ok(Symlinks::Preserve),
+            "follow" => Ok(Symlinks::Follow),
+            "skip" => Ok(Symlinks::Skip),
+            _ => Err(format!(
+                "Unknown symlink policy {}, expected one of preserve, follow or skip",
+                s
+            )),
+        }
+    }
+}
+
+// Worker threads that copy, hash and compress files, `jobs` of them
+pub(crate) fn pool(jobs: usize) -> Result<ThreadPool, Error> {
+    if jobs == 0 {
+        return Err(Error::InvalidInput(
+            "--jobs needs at least one worker".to_string(),
+        ));
+    }
+    ThreadPoolBuilder::new()
+        .num_threads(jobs)
+        .build()
+        .map_err(|e| Error::InvalidInput(format!("Cannot start {} jobs: {}", jobs, e)))
+}
+
+// Files that could not be backed up. Unless keeping going the first one
+// stops the backup, otherwise they are all reported once it is written
+pub(crate) struct Failures {
+    keep_going: bool,
+    failed: Vec<(PathBuf, String)>,
+}
+
+impl Failures {
+    pub(crate) fn new(keep_going: bool) -> Failures {
+        Failures {
+            keep_going,
+            failed: Vec::new(),
+        }
+    }
+    // Note that `path` could not be backed up, an error unless keeping going
+    pub(crate) fn add(&mut self, path: &Path, error: Error) -> Result<(), Error> {
+        if !self.keep_going {
+            return Err(match error {
+                Error::Io(e) => Error::Io(io::Error::new(
+                    e.kind(),
+                    format!("{}: {}", path.display(), e),
+                )),
+                e => e,
+            });
+        }
+        self.failed.push((path.to_path_buf(), error.reason()));
+        Ok(())
+    }
+    pub(crate) fn is_empty(&self) -> bool {
+        self.failed.is_empty()
+    }
+    pub(crate) fn contains(&self, path: &Path) -> bool {
+        self.failed.iter().any(|(failed, _)| failed == path)
+    }
+    // Every failure at once, after the backup has been written
+    pub(crate) fn finish(self) -> Result<(), Error> {
+        if self.failed.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::Incomplete(self.failed))
+        }
+    }
+}
+
+/// Back up `paths` into the directory `out`, or an archive named after it
 pub fn backup(
     paths: &[PathBuf],
     print: &util::VerbosePrint,
-    out: &std::path::Path,
-    dry_run: bool,
-    tar: bool,
-) -> io::Result<()> {
-    if dry_run {
+    out: &Path,
+    options: &BackupOptions,
+) -> Result<(), Error> {
+    if options.encrypt.is_some() && options.repo.is_none() && options.format == archive::Format::Dir
+    {
+        return Err(Error::InvalidInput(
+            "--encrypt only works with tarballs, zips and repositories".to_string(),
+        ));
+    }
+    if options.dry_run && options.incremental.is_none() && options.secrets.is_none() {
         print.println("Files to be backed up:");
         for file in paths {
             print.println(&format!("    {}", file.display()));
         }
         return Ok(());
     }
-    print.println("Backing up");
-    fs::create_dir(&out)?;
-    for file in paths {
-        print.debug(&format!(
-            "Copying file \"{}\" to \"{}\"",
-            file.display(),
-            out.display()
-        ));
-        let parts: Vec<&OsStr> = file.iter().collect();
-        let mut path = OsString::new();
-        if parts.len() > 1 {
-            let dir_path: Vec<PathBuf> = parts
+    if let Some(repo) = options.repo.as_ref().filter(|_| !options.dry_run) {
+        Repository::init(repo)?.backup(paths, print, options)?;
+        return Ok(());
+    }
+    pool(options.jobs)?.install(|| backup_files(paths, print, out, options))
+}
+
+// Back up `paths` on the current worker pool
+fn backup_files(
+    paths: &[PathBuf],
+    print: &VerbosePrint,
+    out: &Path,
+    options: &BackupOptions,
+) -> Result<(), Error> {
+    if !options.dry_run {
+        print.println("Backing up");
+    }
+    let scan = match &options.secrets {
+        Some(scanner) => Some(scanner.scan(paths, options.symlinks, print)?),
+        None => None,
+    };
+    let paths = scan.as_ref().map_or(paths, |scan| &scan.paths[..]);
+    print.debug("Building manifest");
+    let mut failures = Failures::new(options.keep_going);
+    let mut manifest = Manifest::new(paths, options.hash, options.symlinks, &mut failures)?;
+    if let Some(scan) = &scan {
+        scan.apply(&mut manifest)?;
+    }
+    manifest.encryption = options.encrypt.as_ref().map(Encryption::describe);
+    let remaining: Vec<PathBuf>;
+    let paths = match &options.incremental {
+        Some(previous) => {
+            incremental(&mut manifest, previous, &options.keys, print)?;
+            remaining = manifest
+                .entries
+                .iter()
+                .filter(|e| e.base.is_none())
+                .map(|e| e.source.clone())
+                .collect();
+            &remaining[..]
+        }
+        None if !failures.is_empty() => {
+            remaining = paths
                 .iter()
-                .take(parts.len() - 1)
-                .map(OsString::from)
-                .map(PathBuf::from)
+                .filter(|p| !failures.contains(p))
+                .cloned()
                 .collect();
-            for dir in dir_path {
-                path.push(dir);
+            &remaining[..]
+        }
+        None => paths,
+    };
+    if options.dry_run {
+        print.println("Files to be backed up:");
+        for file in paths {
+            print.println(&format!("    {}", file.display()));
+        }
+        if !manifest.deleted.is_empty() {
+            print.println("Deleted since the previous backup:");
+            for stored in &manifest.deleted {
+                print.println(&format!("    {}", original_path(stored).display()));
             }
-            out.to_path_buf().push(&path);
-            dir::create_all(PathBuf::from(&out), false).expect("Failed to create directories");
-        }
-
-        let mut out = PathBuf::from(out);
-        out.push(path);
-        cmd!("mkdir", "-p", &out).stdout_null().run().unwrap();
-        cmd!("cp", "-r", file, &out).stdout_null().run().unwrap();
-    }
-    if tar {
-        print.debug("Executing Tar");
-        cmd(
-            "tar",
-            &[
-                "cjf",
-                &format!("{}.tar.xz", out.display()),
-                &out.display().to_string(),
-            ],
-        )
-        .stdout_null()
-        .run()
-        .unwrap();
-        fs::remove_dir_all(&out)?;
+        }
+        return failures.finish();
+    }
+    let target = backup_file(out, options);
+    if !target.exists() {
+        write(paths, &manifest, print, out, options, &mut failures)?;
+        sign(&target, options, print)?;
+        return failures.finish();
+    }
+    match options.on_existing {
+        OnExisting::Fail => return Err(Error::Exists(target)),
+        OnExisting::Overwrite => replace(
+            paths,
+            &manifest,
+            print,
+            out,
+            &target,
+            options,
+            &mut failures,
+        )?,
+        OnExisting::Rename => {
+            let name = format!("{}-old", out.display());
+            let aside = backup_file(&unused_name(&name, options), options);
+            print.println(&format!(
+                "Moving existing {} to {}",
+                target.display(),
+                aside.display()
+            ));
+            fs::rename(&target, &aside).map_err(Error::with_path(&target))?;
+            let signature = signature::signature_path(&target);
+            if signature.exists() {
+                fs::rename(&signature, signature::signature_path(&aside))
+                    .map_err(Error::with_path(&signature))?;
+            }
+            write(paths, &manifest, print, out, options, &mut failures)?
+        }
+        OnExisting::Merge | OnExisting::Update if options.format != archive::Format::Dir => {
+            return Err(Error::InvalidInput(
+                "--on-existing merge and update only work with directory backups".to_string(),
+            ))
+        }
+        OnExisting::Merge => merge_dir(paths, manifest, print, out, &mut failures)?,
+        OnExisting::Update => update_dir(manifest, print, out, &mut failures)?,
     }
+    sign(&target, options, print)?;
+    failures.finish()
+}
 
+// Sign a backup that has just been written, or remove the signature of the
+// one it replaced
+fn sign(target: &Path, options: &BackupOptions, print: &VerbosePrint) -> Result<(), Error> {
+    match &options.sign {
+        Some(key) => {
+            let signature = key.sign(target)?;
+            print.debug(&format!("Signed as \"{}\"", signature.display()));
+        }
+        None => {
+            let signature = signature::signature_path(target);
+            match fs::remove_file(&signature) {
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+                result => result.map_err(Error::with_path(&signature))?,
+            }
+        }
+    }
+    Ok(())
+}
+
+// Write a new backup to `out` in the chosen format
+fn write(
+    paths: &[PathBuf],
+    manifest: &Manifest,
+    print: &VerbosePrint,
+    out: &Path,
+    options: &BackupOptions,
+    failures: &mut Failures,
+) -> Result<(), Error> {
+    match options.format {
+        archive::Format::Tar => {
+            return archive::write_tar(paths, manifest, print, out, options, failures)
+        }
+        archive::Format::Zip => {
+            return archive::write_zip(paths, manifest, print, out, options, failures)
+        }
+        archive::Format::Dir => (),
+    }
+    let created = attributes::missing_directories(manifest, |stored| out.join(stored));
+    fs::create_dir(out).map_err(Error::with_path(out))?;
+    copy_paths(paths, manifest, print, out, failures)?;
+    attributes::apply_manifest(manifest, |stored| out.join(stored), &created, false)?;
+    manifest.write(&out.join(manifest::MANIFEST_NAME))
+}
+
+// Copy every path into a backup directory, merging with what is already there.
+// Files are copied on the worker pool, then hard links are linked to the copy
+// of the file they share
+fn copy_paths(
+    paths: &[PathBuf],
+    manifest: &Manifest,
+    print: &VerbosePrint,
+    out: &Path,
+    failures: &mut Failures,
+) -> Result<(), Error> {
+    let hardlinks = manifest.hardlinks();
+    let mut copies = Vec::new();
+    let mut links = Vec::new();
+    for path in paths {
+        let files = if path.is_dir() && !is_symlink(path) {
+            dir_files(path, false, manifest.symlinks, false)?
+        } else {
+            vec![path.to_path_buf()]
+        };
+        for file in files {
+            let dest = out.join(backup_path(&file));
+            if let Some(parent) = dest.parent() {
+                fs::create_dir_all(parent).map_err(Error::with_path(parent))?;
+            }
+            match hardlinks.get(file.as_path()) {
+                Some(first) => {
+                    print.debug(&format!(
+                        "Linking file \"{}\" to \"{}\"",
+                        dest.display(),
+                        first.display()
+                    ));
+                    links.push((file, out.join(first), dest));
+                }
+                None => {
+                    print.debug(&format!(
+                        "Copying file \"{}\" to \"{}\"",
+                        file.display(),
+                        dest.display()
+                    ));
+                    copies.push((file, dest));
+                }
+            }
+        }
+    }
+    let copied: Vec<Result<(), Error>> = copies
+        .par_iter()
+        .map(|(file, dest)| match manifest.redacted.get(file) {
+            Some(content) => copy_sparse(&mut &content[..], dest).map(|_| ()),
+            None => copy_file(file, dest, manifest.symlinks),
+        })
+        .collect();
+    for ((file, _), result) in copies.iter().zip(copied) {
+        if let Err(e) = result {
+            failures.add(file, e)?;
+        }
+    }
+    for (file, first, dest) in links {
+        if let Err(e) = hard_link(&first, &dest) {
+            failures.add(&file, e)?;
+        }
+    }
+    Ok(())
+}
+
+// Copy a single file, or the link itself when links are not followed
+fn copy_file(file: &Path, dest: &Path, symlinks: Symlinks) -> Result<(), Error> {
+    if symlinks != Symlinks::Follow && is_symlink(file) {
+        let target = fs::read_link(file).map_err(Error::with_path(file))?;
+        return symlink(&target, dest);
+    }
+    let mut reader = File::open(file).map_err(Error::with_path(file))?;
+    copy_sparse(&mut reader, dest)?;
     Ok(())
 }
 
-pub fn all_paths(dir: DirContent) -> Vec<PathBuf> {
-    let paths = dir.files.iter().map(|f| PathBuf::from(f)).collect();
-    paths
+// Write the new backup next to the existing one and swap them once it is
+// complete, so `target` is never left half written
+fn replace(
+    paths: &[PathBuf],
+    manifest: &Manifest,
+    print: &VerbosePrint,
+    out: &Path,
+    target: &Path,
+    options: &BackupOptions,
+    failures: &mut Failures,
+) -> Result<(), Error> {
+    let name = out
+        .file_name()
+        .ok_or_else(|| Error::InvalidInput(format!("Invalid backup name {}", out.display())))?;
+    let parent = match out.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let staging = parent.join(format!(".confbk-{}", std::process::id()));
+    fs::create_dir(&staging).map_err(Error::with_path(&staging))?;
+    let staged = staging.join(name);
+    let result = write(paths, manifest, print, &staged, options, failures).and_then(|()| {
+        print.debug(&format!("Replacing \"{}\"", target.display()));
+        let old = staging.join("old");
+        fs::rename(target, &old).map_err(Error::with_path(target))?;
+        let new = backup_file(&staged, options);
+        if let Err(e) = fs::rename(&new, target) {
+            // put the old backup back before the staging directory goes
+            if let Err(back) = fs::rename(&old, target) {
+                return Err(Error::InvalidInput(format!(
+                    "Cannot replace {}: {}, and the old backup could not be moved back \
+                     from {}: {}",
+                    target.display(),
+                    e,
+                    old.display(),
+                    back
+                )));
+            }
+            return Err(Error::with_path(target)(e));
+        }
+        Ok(())
+    });
+    // the old backup is kept when it could not be moved back
+    if staging.join("old").exists() && result.is_err() {
+        return result;
+    }
+    fs::remove_dir_all(&staging).map_err(Error::with_path(&staging))?;
+    result
+}
+
+// Copy every path into an existing backup directory. Files already in it that
+// are not part of this backup are kept, and so are their manifest entries
+fn merge_dir(
+    paths: &[PathBuf],
+    mut manifest: Manifest,
+    print: &VerbosePrint,
+    out: &Path,
+    failures: &mut Failures,
+) -> Result<(), Error> {
+    print.debug(&format!("Merging into \"{}\"", out.display()));
+    let created = attributes::missing_directories(&manifest, |stored| out.join(stored));
+    copy_paths(paths, &manifest, print, out, failures)?;
+    if let Some(existing) = archive::read_manifest(out, &Keys::default())? {
+        let new: HashSet<PathBuf> = manifest.entries.iter().map(|e| e.stored.clone()).collect();
+        manifest.entries.extend(
+            existing
+                .entries
+                .into_iter()
+                .filter(|e| !new.contains(&e.stored)),
+        );
+        manifest.entries.sort_by(|a, b| a.stored.cmp(&b.stored));
+    }
+    attributes::apply_manifest(&manifest, |stored| out.join(stored), &created, false)?;
+    manifest.write(&out.join(manifest::MANIFEST_NAME))
+}
+
+// Bring an existing backup directory up to date, copying only files whose hash
+// changed and removing files that are no longer backed up
+fn update_dir(
+    manifest: Manifest,
+    print: &VerbosePrint,
+    out: &Path,
+    failures: &mut Failures,
+) -> Result<(), Error> {
+    let mut existing: BTreeMap<PathBuf, String> =
+        match archive::read_manifest(out, &Keys::default())? {
+            Some(existing) => existing
+                .entries
+                .into_iter()
+                .map(|e| (e.stored, e.hash))
+                .collect(),
+            None => BTreeMap::new(),
+        };
+    let created = attributes::missing_directories(&manifest, |stored| out.join(stored));
+    let (mut changed, mut unchanged) = (0, 0);
+    for entry in &manifest.entries {
+        let dest = out.join(&entry.stored);
+        let old = existing.remove(&entry.stored);
+        if entry.base.is_some() {
+            continue;
+        }
+        if old.as_ref() == Some(&entry.hash) && fs::symlink_metadata(&dest).is_ok() {
+            unchanged += 1;
+            continue;
+        }
+        print.debug(&format!(
+            "Updating file \"{}\" from \"{}\"",
+            dest.display(),
+            entry.source.display()
+        ));
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent).map_err(Error::with_path(parent))?;
+        }
+        match &entry.hardlink {
+            Some(first) => hard_link(&out.join(first), &dest)?,
+            None => {
+                let copied = match manifest.redacted.get(&entry.source) {
+                    Some(content) => copy_sparse(&mut &content[..], &dest).map(|_| ()),
+                    None => copy_file(&entry.source, &dest, manifest.symlinks),
+                };
+                if let Err(e) = copied {
+                    failures.add(&entry.source, e)?;
+                    continue;
+                }
+            }
+        }
+        changed += 1;
+    }
+    for stored in existing.keys() {
+        let dest = out.join(stored);
+        print.debug(&format!("Removing file \"{}\"", dest.display()));
+        match fs::remove_file(&dest) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+            result => result.map_err(Error::with_path(&dest))?,
+        }
+    }
+    print.println(&format!(
+        "{} updated, {} unchanged and {} removed",
+        changed,
+        unchanged,
+        existing.len()
+    ));
+    attributes::apply_manifest(&manifest, |stored| out.join(stored), &created, false)?;
+    manifest.write(&out.join(manifest::MANIFEST_NAME))
+}
+
+// File or directory a backup named `out` is written to
+fn backup_file(out: &Path, options: &BackupOptions) -> PathBuf {
+    let encrypted = options.encrypt.is_some();
+    match options.format {
+        archive::Format::Dir => out.to_path_buf(),
+        archive::Format::Tar => archive::archive_path(out, options.compression, encrypted),
+        archive::Format::Zip => archive::zip_path(out, encrypted),
+    }
+}
+
+// `name`, or the first of `name-1`, `name-2`... that no backup is using
+fn unused_name(name: &str, options: &BackupOptions) -> PathBuf {
+    let mut out = PathBuf::from(name);
+    let mut n = 1;
+    while backup_file(&out, options).exists() {
+        out = PathBuf::from(format!("{}-{}", name, n));
+        n += 1;
+    }
+    out
+}
+
+/// Where to write a backup named by `template`. `{date}`, `{time}`,
+/// `{hostname}` and `{profile}` are filled in along with strftime fields such
+/// as `%Y`. With `--on-existing fail`, names made from a template get a `-1`,
+/// `-2`... suffix when a backup of that name already exists, other policies
+/// apply to the backup of that name instead
+pub fn out_path(
+    template: &Path,
+    profile: Option<&str>,
+    options: &BackupOptions,
+) -> Result<PathBuf, Error> {
+    let text = match template.to_str() {
+        Some(text) if text.contains('{') || text.contains('%') => text,
+        _ => return Ok(template.to_path_buf()),
+    };
+    let name = expand_template(text, profile)?;
+    if options.on_existing != OnExisting::Fail {
+        return Ok(PathBuf::from(name));
+    }
+    Ok(unused_name(&name, options))
+}
+
+// Fill in the fields of an output name template with the current local time
+fn expand_template(template: &str, profile: Option<&str>) -> Result<String, Error> {
+    let mut format = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        format.push_str(&rest[..start]);
+        let end = rest[start..].find('}').ok_or_else(|| {
+            Error::InvalidInput(format!("Unclosed {{ in output name {}", template))
+        })?;
+        let value = match &rest[start + 1..start + end] {
+            "date" => "%Y_%m_%d".to_string(),
+            "time" => "%H_%M_%S".to_string(),
+            "hostname" => hostname::get()?.to_string_lossy().replace('%', "%%"),
+            "profile" => profile.unwrap_or("default").replace('%', "%%"),
+            field => {
+                return Err(Error::InvalidInput(format!(
+                "Unknown field {{{}}} in output name {}, expected date, time, hostname or profile",
+                field, template
+            )))
+            }
+        };
+        format.push_str(&value);
+        rest = &rest[start + end + 1..];
+    }
+    format.push_str(rest);
+    let items: Vec<Item> = StrftimeItems::new(&format).collect();
+    if items.contains(&Item::Error) {
+        return Err(Error::InvalidInput(format!(
+            "Invalid strftime field in output name {}",
+            template
+        )));
+    }
+    Ok(Local::now()
+        .format_with_items(items.into_iter())
+        .to_string())
+}
+
+// Compare a new manifest with the one of the backup it is incremental against
+fn incremental(
+    manifest: &mut Manifest,
+    previous: &Path,
+    keys: &Keys,
+    print: &VerbosePrint,
+) -> Result<(), Error> {
+    print.debug(&format!("Comparing with \"{}\"", previous.display()));
+    let earlier = archive::read_manifest(previous, keys)?
+        .ok_or_else(|| Error::Manifest(format!("{} has no manifest", previous.display())))?;
+    manifest.incremental(&earlier, &std::path::absolute(previous)?);
+    let unchanged = manifest.entries.iter().filter(|e| e.base.is_some()).count();
+    print.println(&format!(
+        "{} changed, {} unchanged and {} deleted since {}",
+        manifest.entries.len() - unchanged,
+        unchanged,
+        manifest.deleted.len(),
+        previous.display()
+    ));
+    Ok(())
+}
+
+// Every file under a directory in name order. Unless `ignore_files` is false,
+// anything matched by a .gitignore, .ignore or IGNORE_FILE in the tree is
+// skipped. Links are listed as links when preserved, and when followed those
+// that loop back to a directory above them or point nowhere are left out.
+// When keeping going, directories that can't be read are listed in place of
+// their files, so backing them up fails and they are reported with the rest
+pub(crate) fn dir_files(
+    dir: &Path,
+    ignore_files: bool,
+    symlinks: Symlinks,
+    keep_going: bool,
+) -> Result<Vec<PathBuf>, Error> {
+    let mut builder = WalkBuilder::new(dir);
+    builder
+        .standard_filters(false)
+        .git_ignore(ignore_files)
+        .ignore(ignore_files)
+        .require_git(false)
+        .follow_links(symlinks == Symlinks::Follow)
+        .sort_by_file_name(|a, b| a.cmp(b));
+    if ignore_files {
+        builder.add_custom_ignore_filename(IGNORE_FILE);
+    }
+    let mut files = Vec::new();
+    for entry in builder.build() {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(ref e) if symlinks == Symlinks::Follow && broken_link(e) => continue,
+            Err(e) => {
+                let path = error_path(&e).unwrap_or(dir).to_path_buf();
+                if keep_going {
+                    files.push(path);
+                    continue;
+                }
+                let message = e.to_string();
+                return Err(match e.into_io_error() {
+                    Some(e) => Error::with_path(&path)(e),
+                    None => Error::Io(io::Error::other(message)),
+                });
+            }
+        };
+        let keep = match entry.file_type() {
+            Some(file_type) if file_type.is_symlink() => symlinks == Symlinks::Preserve,
+            Some(file_type) => file_type.is_file(),
+            None => false,
+        };
+        if keep {
+            files.push(entry.into_path());
+        }
+    }
+    Ok(files)
+}
+
+// Path a walk error happened on
+fn error_path(error: &ignore::Error) -> Option<&Path> {
+    match error {
+        ignore::Error::WithPath { path, .. } => Some(path),
+        ignore::Error::WithDepth { err, .. } | ignore::Error::WithLineNumber { err, .. } => {
+            error_path(err)
+        }
+        _ => None,
+    }
+}
+
+// Error number for too many levels of symbolic links on Linux, its kind is
+// not stable yet so it is compared through an error made from it
+const ELOOP: i32 = 40;
+
+// Whether walking failed on a link that loops or points to nothing
+fn broken_link(error: &ignore::Error) -> bool {
+    match error {
+        ignore::Error::Loop { .. } => true,
+        ignore::Error::WithPath { err, .. } | ignore::Error::WithDepth { err, .. } => {
+            broken_link(err)
+        }
+        // a chain of links leading back to itself never resolves
+        ignore::Error::Io(e) => {
+            e.kind() == io::ErrorKind::NotFound
+                || e.kind() == io::Error::from_raw_os_error(ELOOP).kind()
+        }
+        _ => false,
+    }
+}
+
+// Whether `path` is a symbolic link, without following it
+pub(crate) fn is_symlink(path: &Path) -> bool {
+    fs::symlink_metadata(path)
+        .map(|m| m.file_type().is_symlink())
+        .unwrap_or(false)
+}
+
+// Create a link at `dest` pointing to `target`, replacing a file or link
+// already there
+pub(crate) fn symlink(target: &Path, dest: &Path) -> Result<(), Error> {
+    remove_existing(dest)?;
+    unix_fs::symlink(target, dest).map_err(Error::with_path(dest))
+}
+
+// Make `dest` a hard link to `file`, replacing a file or link already there
+pub(crate) fn hard_link(file: &Path, dest: &Path) -> Result<(), Error> {
+    remove_existing(dest)?;
+    fs::hard_link(file, dest).map_err(Error::with_path(dest))
+}
+
+// Remove a file or link already at `dest`, which may be read only once its
+// recorded mode was applied
+fn remove_existing(dest: &Path) -> Result<(), Error> {
+    match fs::symlink_metadata(dest) {
+        Ok(metadata) if !metadata.is_dir() => fs::remove_file(dest).map_err(Error::with_path(dest)),
+        _ => Ok(()),
+    }
+}
+
+// Write everything left in `reader` to a new file at `dest`, replacing a file
+// or link already there. Blocks that are all zeros are seeked over so they
+// become holes and sparse files stay sparse
+pub(crate) fn copy_sparse<R: Read + ?Sized>(reader: &mut R, dest: &Path) -> Result<u64, Error> {
+    const BLOCK: usize = 4096;
+    remove_existing(dest)?;
+    let mut file = File::create(dest).map_err(Error::with_path(dest))?;
+    let mut buffer = vec![0; 16 * BLOCK];
+    let mut length = 0;
+    loop {
+        // fill the whole buffer so blocks line up with the file system's
+        let mut filled = 0;
+        while filled < buffer.len() {
+            match reader.read(&mut buffer[filled..]) {
+                Ok(0) => break,
+                Ok(read) => filled += read,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        if filled == 0 {
+            break;
+        }
+        for block in buffer[..filled].chunks(BLOCK) {
+            if block.iter().all(|b| *b == 0) {
+                file.seek(SeekFrom::Current(block.len() as i64))?;
+            } else {
+                file.write_all(block).map_err(Error::with_path(dest))?;
+            }
+        }
+        length += filled as u64;
+    }
+    // a trailing hole is only there once the length covers it
+    file.set_len(length).map_err(Error::with_path(dest))?;
+    Ok(length)
+}
+
+// Size in bytes in the largest binary unit that keeps it at 1 or more
+pub(crate) fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+// Location of a path inside a backup, relative to the backup root.
+// Relative paths are kept as is, anything else is placed under ABSOLUTE_ROOT.
+// A relative path starting with ABSOLUTE_ROOT, or a name made of it and more
+// leading underscores, gets one more underscore so the two never meet
+pub(crate) fn backup_path(path: &Path) -> PathBuf {
+    let relative = path
+        .components()
+        .all(|c| matches!(c, Component::Normal(_) | Component::CurDir));
+    if relative {
+        let mut components = path.components().filter(|c| *c != Component::CurDir);
+        let mut stored = PathBuf::new();
+        if let Some(first) = components.next() {
+            let first = first.as_os_str();
+            if is_root_name(first) {
+                let mut escaped = OsString::from("_");
+                escaped.push(first);
+                stored.push(escaped);
+            } else {
+                stored.push(first);
+            }
+        }
+        stored.extend(components);
+        return stored;
+    }
+    let absolute = if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        std::env::current_dir()
+            .map(|d| d.join(path))
+            .unwrap_or_else(|_| path.to_path_buf())
+    };
+    let mut stored = PathBuf::from(ABSOLUTE_ROOT);
+    for component in absolute.components() {
+        match component {
+            Component::Normal(c) => stored.push(c),
+            Component::ParentDir if stored != Path::new(ABSOLUTE_ROOT) => {
+                stored.pop();
+            }
+            _ => (),
+        }
+    }
+    stored
+}
+
+// Inverse of backup_path, maps a path inside a backup to where it came from
+pub(crate) fn original_path(stored: &Path) -> PathBuf {
+    if let Ok(rest) = stored.strip_prefix(ABSOLUTE_ROOT) {
+        return Path::new("/").join(rest);
+    }
+    let mut components = stored.components();
+    match components.next() {
+        Some(Component::Normal(first)) if is_root_name(first) => {
+            let mut original = PathBuf::from(&first.to_string_lossy()[1..]);
+            original.extend(components);
+            original
+        }
+        _ => stored.to_path_buf(),
+    }
+}
+
+// Whether a name is ABSOLUTE_ROOT with any number of extra underscores in front
+fn is_root_name(name: &OsStr) -> bool {
+    match name.to_str().and_then(|name| name.strip_prefix('_')) {
+        Some(rest) => rest.trim_start_matches('_') == &ABSOLUTE_ROOT[1..],
+        None => false,
+    }
 }
@@ -0,0 +1,89 @@
+use super::archive;
+use super::crypto::Keys;
+use super::error::Error;
+use super::manifest::{self, Manifest};
+use super::signature::VerifyingKey;
+use super::util;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// Check a backup against the hashes in its manifest, listing every difference.
+/// Encrypted backups are decrypted with `keys` first, and with `signature`
+/// the backup has to carry a matching detached signature
+pub fn verify(
+    backup: &Path,
+    print: &util::VerbosePrint,
+    keys: &Keys,
+    signature: Option<&VerifyingKey>,
+) -> Result<(), Error> {
+    if !backup.exists() {
+        return Err(Error::NotFound(backup.to_path_buf()));
+    }
+    print.println("Verifying");
+    if let Some(key) = signature {
+        key.verify(backup)?;
+        print.println("Signature verified");
+    }
+    let manifest: Manifest = archive::read_manifest(backup, keys)?
+        .ok_or_else(|| Error::Manifest(format!("{} has no manifest", backup.display())))?;
+    print.debug(&format!("Hashing files with {}", manifest.hash_algorithm));
+
+    let mut hashes: BTreeMap<PathBuf, String> = BTreeMap::new();
+    archive::walk(backup, keys, |stored, reader| {
+        if stored != Path::new(manifest::MANIFEST_NAME) {
+            let hash = manifest::hash_reader(reader, manifest.hash_algorithm)?;
+            hashes.insert(stored.to_path_buf(), hash);
+        }
+        Ok(())
+    })?;
+
+    let mut failures = 0;
+    let mut verified = 0;
+    for entry in &manifest.entries {
+        if let Some(base) = &entry.base {
+            print.debug(&format!(
+                "Skipping \"{}\", it is stored in \"{}\"",
+                entry.stored.display(),
+                base.display()
+            ));
+            continue;
+        }
+        if let Some(first) = &entry.hardlink {
+            // the contents are checked with the file it links to
+            hashes.remove(&entry.stored);
+            print.debug(&format!(
+                "Skipping \"{}\", it is a link to \"{}\"",
+                entry.stored.display(),
+                first.display()
+            ));
+            continue;
+        }
+        verified += 1;
+        match hashes.remove(&entry.stored) {
+            Some(ref hash) if *hash == entry.hash => {
+                print.debug(&format!("Verified \"{}\"", entry.stored.display()))
+            }
+            Some(_) => {
+                print.println(&format!("Modified: {}", entry.stored.display()));
+                failures += 1;
+            }
+            None => {
+                print.println(&format!("Missing: {}", entry.stored.display()));
+                failures += 1;
+            }
+        }
+    }
+    for extra in hashes.keys() {
+        print.println(&format!("Extra: {}", extra.display()));
+        failures += 1;
+    }
+
+    if failures > 0 {
+        return Err(Error::Verification {
+            backup: backup.to_path_buf(),
+            problems: failures,
+        });
+    }
+    print.println(&format!("Verified {} files", verified));
+    Ok(())
+}